@@ -1,58 +1,258 @@
-use std::collections::{HashSet};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 
 use crate::{FileName, MarkDownloadedPartition, MediaItemId, StoredItem, StoredItemStore};
+use crate::integrity::FileDigest;
+use crate::downloader::DownloadedCopy;
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadOrder {
+    NewestFirst,
+    OldestFirst,
+    PhotosFirst,
+    SmallestFirst,
+    AlbumPriority,
+}
+
+pub struct DownloadOrdering<'a> {
+    pub order: &'a [DownloadOrder],
+    pub priority_albums: &'a [String],
+}
+
+impl<'a> DownloadOrdering<'a> {
+    // keys are applied in order, media item id breaks ties so the selection is the same after a restart
+    fn compare(&self, a: &StoredItem, b: &StoredItem) -> Ordering {
+        self.order
+            .iter()
+            .map(|key| match key {
+                DownloadOrder::NewestFirst => b.creation_time().cmp(&a.creation_time()),
+                DownloadOrder::OldestFirst => a.creation_time().cmp(&b.creation_time()),
+                DownloadOrder::PhotosFirst => b.is_photo().cmp(&a.is_photo()),
+                DownloadOrder::SmallestFirst => a.estimated_size().cmp(&b.estimated_size()),
+                DownloadOrder::AlbumPriority => self.album_rank(a).cmp(&self.album_rank(b)),
+            })
+            .fold(Ordering::Equal, Ordering::then)
+            .then_with(|| a.mediaItem.id.cmp(&b.mediaItem.id))
+    }
+
+    fn album_rank(&self, stored_item: &StoredItem) -> usize {
+        stored_item.albums
+            .iter()
+            .flatten()
+            .filter_map(|album| self.priority_albums.iter().position(|priority| priority == album))
+            .min()
+            .unwrap_or(self.priority_albums.len())
+    }
+}
+
+// replica None is the primary destination, see Destination::replica
 pub trait AppStorage {
-    fn select_files_for_download(&self, limit: usize) -> Vec<StoredItem>;
+    fn select_files_for_download(&self, limit: usize, ordering: &DownloadOrdering, replicas: &[&str]) -> Vec<StoredItem>;
+
+    fn set_album_items(&mut self, album_id: &str, media_item_ids: &[MediaItemId]);
+
+    fn mark_downloaded(&mut self, replica: Option<&str>, downloaded: &[(MediaItemId, FileDigest)]);
+
+    fn mark_copies(&mut self, copies: &[DownloadedCopy]);
 
-    fn mark_downloaded(&mut self, media_item_ids: &Vec<MediaItemId>);
+    fn set_digests(&mut self, replica: Option<&str>, digests: &[(MediaItemId, FileDigest)]);
 
-    fn unmark_downloaded(&mut self, media_item_ids: &Vec<MediaItemId>);
+    fn unmark_downloaded(&mut self, replica: Option<&str>, media_item_ids: &[MediaItemId]);
 
-    fn partition_by_marked_download(&self, fs_file_names: &HashSet<FileName>) -> MarkDownloadedPartition;
+    fn partition_by_marked_download(&self, replica: Option<&str>, fs_files: &HashMap<FileName, u64>, fold_case: bool)
+                                    -> MarkDownloadedPartition;
+
+    fn downloaded_size(&self, replica: Option<&str>) -> u64;
 }
 
 impl AppStorage for StoredItemStore {
-    fn select_files_for_download(&self, limit: usize) -> Vec<StoredItem> {
-        self.filter_values(|(_, v)| {
-            !v.is_marked_downloaded()
-        }, Some(limit))
+    fn select_files_for_download(&self, limit: usize, ordering: &DownloadOrdering, replicas: &[&str]) -> Vec<StoredItem> {
+        let mut not_downloaded = self.data
+            .values()
+            .filter(|v| !v.is_downloaded_everywhere(replicas))
+            .collect::<Vec<_>>();
+
+        not_downloaded.sort_by(|a, b| ordering.compare(a, b));
+
+        not_downloaded
+            .into_iter()
+            .take(limit)
+            .filter_map(|v| self.get_cloned(&v.mediaItem.id))
+            .collect()
     }
 
-    fn mark_downloaded(&mut self, media_item_ids: &Vec<MediaItemId>) {
-        for id in media_item_ids {
+    fn set_album_items(&mut self, album_id: &str, media_item_ids: &[MediaItemId]) {
+        let in_album: HashSet<&MediaItemId> = media_item_ids.iter().collect();
+
+        for (id, stored_item) in self.data.iter_mut() {
+            let mut albums = stored_item.albums.take().unwrap_or_default();
+            albums.retain(|album| album != album_id);
+
+            if in_album.contains(id) {
+                albums.push(album_id.to_owned());
+            }
+
+            stored_item.albums = if albums.is_empty() { None } else { Some(albums) };
+        }
+    }
+
+    fn mark_downloaded(&mut self, replica: Option<&str>, downloaded: &[(MediaItemId, FileDigest)]) {
+        for (id, digest) in downloaded {
+            if let Some(stored_item) = self.data.get_mut(id) {
+                stored_item.mark_downloaded_at(replica, digest);
+            }
+        }
+    }
+
+    fn mark_copies(&mut self, copies: &[DownloadedCopy]) {
+        for copy in copies {
+            if let Some(stored_item) = self.data.get_mut(&copy.id) {
+                stored_item.mark_downloaded_at(copy.replica.as_deref(), &copy.digest);
+                if let Some(linked_to) = copy.linked_to.as_ref() {
+                    stored_item.mark_linked_at(copy.replica.as_deref(), linked_to);
+                }
+            }
+        }
+    }
+
+    fn set_digests(&mut self, replica: Option<&str>, digests: &[(MediaItemId, FileDigest)]) {
+        for (id, digest) in digests {
             if let Some(stored_item) = self.data.get_mut(id) {
-                stored_item.mark_downloaded();
+                stored_item.set_digest_at(replica, digest);
             }
         }
     }
 
-    fn unmark_downloaded(&mut self, media_item_ids: &Vec<MediaItemId>) {
+    fn unmark_downloaded(&mut self, replica: Option<&str>, media_item_ids: &[MediaItemId]) {
         for id in media_item_ids {
             if let Some(stored_item) = self.data.get_mut(id) {
-                stored_item.unmark_downloaded();
+                stored_item.unmark_downloaded_at(replica);
             }
         }
     }
 
-    fn partition_by_marked_download(&self, fs_file_names: &HashSet<FileName>) -> MarkDownloadedPartition {
+    fn partition_by_marked_download(&self, replica: Option<&str>, fs_files: &HashMap<FileName, u64>, fold_case: bool)
+                                    -> MarkDownloadedPartition
+    {
         let mut partition = MarkDownloadedPartition {
             mark_downloaded: Vec::new(),
             unmark_downloaded: Vec::new()
         };
 
         self.data.iter().for_each(|(k, v)| {
-            let is_in_fs = fs_file_names.contains(v.get_filename().as_str());
+            let filename = if fold_case { v.get_filename().to_lowercase() } else { v.get_filename() };
+            let fs_size = fs_files.get(filename.as_str()).cloned();
+            let download_info = v.get_download_info_at(replica);
+            let recorded_size = download_info.and_then(|info| info.size);
+
+            // empty files and files whose size differs from the one recorded at download are not complete
+            let is_in_fs = match (fs_size, recorded_size) {
+                (Some(0), _) => false,
+                (Some(fs_size), Some(recorded_size)) => fs_size == recorded_size,
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
 
-            if is_in_fs && !v.is_marked_downloaded() {
-                partition.mark_downloaded.push(k.to_owned());
+            if is_in_fs && download_info.is_none() {
+                partition.mark_downloaded.push((k.to_owned(), FileDigest::size_only(fs_size.unwrap())));
             }
 
-            if !is_in_fs && v.is_marked_downloaded() {
+            if !is_in_fs && download_info.is_some() {
                 partition.unmark_downloaded.push(k.to_owned());
             }
         });
 
         partition
     }
+
+    fn downloaded_size(&self, replica: Option<&str>) -> u64 {
+        self.data
+            .values()
+            .filter_map(|v| v.get_download_info_at(replica))
+            .filter(|info| info.linked_to.is_none())
+            .filter_map(|info| info.size)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::StoredItemBuilder;
+
+    fn store_with(ids: &[&str]) -> StoredItemStore {
+        let path = std::env::temp_dir().join("rs-google-photos-sync-replicas.data");
+        let _ = std::fs::remove_file(&path);
+        let mut storage = StoredItemStore::new(path.to_str().unwrap());
+
+        for id in ids {
+            StoredItemBuilder::new(id, &format!("{}.jpg", id)).add_to(&mut storage);
+        }
+
+        storage
+    }
+
+    #[test]
+    pub fn destinations_are_reconciled_separately() {
+        let mut storage = store_with(&["a", "b"]);
+        storage.mark_downloaded(None, &[("a".to_owned(), FileDigest::size_only(3))]);
+        storage.mark_downloaded(Some("usb"), &[("a".to_owned(), FileDigest::size_only(3))]);
+
+        let usb_files = [("b.jpg".to_owned(), 5)].iter().cloned().collect::<HashMap<_, _>>();
+        let partition = storage.partition_by_marked_download(Some("usb"), &usb_files, false);
+
+        assert_eq!(partition.mark_downloaded, vec![("b".to_owned(), FileDigest::size_only(5))]);
+        assert_eq!(partition.unmark_downloaded, vec!["a".to_owned()]);
+
+        storage.unmark_downloaded(Some("usb"), &partition.unmark_downloaded);
+
+        let a = storage.get(&"a".to_owned()).unwrap();
+        assert!(a.is_marked_downloaded());
+        assert!(!a.is_downloaded_everywhere(&["usb"]));
+        assert_eq!(storage.select_files_for_download(10, &DownloadOrdering { order: &[], priority_albums: &[] }, &["usb"]).len(), 2);
+    }
+
+    fn ordered(order: &[DownloadOrder], priority_albums: &[&str]) -> Vec<MediaItemId> {
+        let stored_items = [
+            StoredItemBuilder::new("a", "a.jpg").created_on(3).dimensions(10, 10).albums(&["trip"]).build(),
+            StoredItemBuilder::new("b", "b.mp4").created_on(1).video().build(),
+            StoredItemBuilder::new("c", "c.jpg").created_on(2).dimensions(100, 100).albums(&["family"]).build(),
+            StoredItemBuilder::new("d", "d.jpg").created_on(2).dimensions(10, 10).build(),
+        ];
+        let priority_albums = priority_albums.iter().map(|album| album.to_string()).collect::<Vec<_>>();
+        let ordering = DownloadOrdering { order, priority_albums: &priority_albums };
+
+        let mut sorted = stored_items.iter().collect::<Vec<_>>();
+        sorted.sort_by(|a, b| ordering.compare(a, b));
+
+        sorted.into_iter().map(|stored_item| stored_item.mediaItem.id.to_owned()).collect()
+    }
+
+    #[test]
+    pub fn newest_first_breaks_ties_by_id() {
+        assert_eq!(ordered(&[DownloadOrder::NewestFirst], &[]), ["a", "c", "d", "b"]);
+    }
+
+    #[test]
+    pub fn oldest_first_breaks_ties_by_id() {
+        assert_eq!(ordered(&[DownloadOrder::OldestFirst], &[]), ["b", "c", "d", "a"]);
+    }
+
+    #[test]
+    pub fn photos_first_puts_videos_last() {
+        assert_eq!(ordered(&[DownloadOrder::PhotosFirst], &[]), ["a", "c", "d", "b"]);
+        assert_eq!(ordered(&[DownloadOrder::PhotosFirst, DownloadOrder::OldestFirst], &[]), ["c", "d", "a", "b"]);
+    }
+
+    #[test]
+    pub fn smallest_first_orders_photos_by_pixels_and_videos_last() {
+        assert_eq!(ordered(&[DownloadOrder::SmallestFirst], &[]), ["a", "d", "c", "b"]);
+    }
+
+    #[test]
+    pub fn album_priority_follows_the_priority_albums() {
+        assert_eq!(ordered(&[DownloadOrder::AlbumPriority], &["family", "trip"]), ["c", "a", "b", "d"]);
+        assert_eq!(ordered(&[DownloadOrder::AlbumPriority, DownloadOrder::NewestFirst], &["trip"]), ["a", "c", "d", "b"]);
+    }
 }
@@ -0,0 +1,201 @@
+use std::time::{Duration, Instant};
+
+use chrono::{Local, NaiveTime};
+use tokio::sync::Mutex;
+
+use crate::error::{CustomError, CustomResult};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BandwidthLimit {
+    pub bytes_per_second: Option<u64>,
+    pub schedule: Option<Vec<BandwidthWindow>>,
+}
+
+// from and to are local "HH:MM", a window may wrap around midnight (22:00 - 06:00)
+#[derive(Deserialize, Debug, Clone)]
+pub struct BandwidthWindow {
+    pub from: String,
+    pub to: String,
+    pub bytes_per_second: Option<u64>,
+}
+
+struct Window {
+    from: NaiveTime,
+    to: NaiveTime,
+    bytes_per_second: Option<u64>,
+}
+
+impl Window {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            time >= self.from || time < self.to
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+// Token bucket shared by all download workers. The bucket holds at most one second worth of bytes,
+// a worker takes tokens for every chunk it writes and sleeps while the bucket is in debt.
+pub struct BandwidthLimiter {
+    default_rate: Option<u64>,
+    windows: Vec<Window>,
+    bucket: Mutex<Bucket>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(limit: &Option<BandwidthLimit>) -> CustomResult<BandwidthLimiter> {
+        let mut windows = Vec::new();
+
+        if let Some(schedule) = limit.as_ref().and_then(|limit| limit.schedule.as_ref()) {
+            for window in schedule {
+                windows.push(Window {
+                    from: parse_time(&window.from)?,
+                    to: parse_time(&window.to)?,
+                    bytes_per_second: window.bytes_per_second,
+                });
+            }
+        }
+
+        Ok(BandwidthLimiter {
+            default_rate: limit.as_ref().and_then(|limit| limit.bytes_per_second),
+            windows,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                refilled_at: Instant::now(),
+            }),
+        })
+    }
+
+    fn rate_at(&self, time: NaiveTime) -> Option<u64> {
+        match self.windows.iter().find(|window| window.contains(time)) {
+            Some(window) => window.bytes_per_second,
+            None => self.default_rate,
+        }
+    }
+
+    pub async fn acquire(&self, bytes: usize) {
+        let rate = match self.rate_at(Local::now().time()) {
+            Some(rate) if rate > 0 => rate as f64,
+            _ => return,
+        };
+
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.refilled_at = now;
+
+            bucket.tokens -= bytes as f64;
+
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            } else {
+                Duration::from_secs(0)
+            }
+        };
+
+        if wait > Duration::from_secs(0) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+fn parse_time(time: &str) -> CustomResult<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|e| CustomError::Err(format!("invalid bandwidth schedule time {} {}", time, e)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limiter(schedule: Vec<(&str, &str, Option<u64>)>) -> BandwidthLimiter {
+        BandwidthLimiter::new(&Some(BandwidthLimit {
+            bytes_per_second: Some(100),
+            schedule: Some(schedule
+                .into_iter()
+                .map(|(from, to, bytes_per_second)| BandwidthWindow {
+                    from: from.to_owned(),
+                    to: to.to_owned(),
+                    bytes_per_second,
+                })
+                .collect()),
+        })).unwrap()
+    }
+
+    fn at(time: &str) -> NaiveTime {
+        parse_time(time).unwrap()
+    }
+
+    #[test]
+    pub fn schedule_window_overrides_default_rate() {
+        let limiter = limiter(vec![("09:00", "17:00", Some(2_000_000))]);
+
+        assert_eq!(limiter.rate_at(at("08:59")), Some(100));
+        assert_eq!(limiter.rate_at(at("09:00")), Some(2_000_000));
+        assert_eq!(limiter.rate_at(at("17:00")), Some(100));
+    }
+
+    #[test]
+    pub fn window_wraps_around_midnight() {
+        let limiter = limiter(vec![("22:00", "06:00", None)]);
+
+        assert_eq!(limiter.rate_at(at("23:30")), None);
+        assert_eq!(limiter.rate_at(at("05:59")), None);
+        assert_eq!(limiter.rate_at(at("12:00")), Some(100));
+    }
+
+    fn acquire_all(limiter: &BandwidthLimiter, chunks: Vec<usize>) -> Duration {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let started_at = Instant::now();
+        runtime.block_on(futures::future::join_all(chunks.into_iter().map(|bytes| limiter.acquire(bytes))));
+
+        started_at.elapsed()
+    }
+
+    #[test]
+    pub fn acquire_waits_for_the_bytes_over_the_rate() {
+        let limiter = BandwidthLimiter::new(&Some(BandwidthLimit { bytes_per_second: Some(10_000), schedule: None })).unwrap();
+
+        let waited = acquire_all(&limiter, vec![1_000, 1_000]);
+        assert!(waited >= Duration::from_millis(180), "{:?}", waited);
+        assert!(waited < Duration::from_millis(600), "{:?}", waited);
+    }
+
+    #[test]
+    pub fn acquire_is_shared_by_all_workers() {
+        let limiter = BandwidthLimiter::new(&Some(BandwidthLimit { bytes_per_second: Some(10_000), schedule: None })).unwrap();
+
+        // four workers at once take as long as one worker with all of their bytes
+        let waited = acquire_all(&limiter, vec![500, 500, 500, 500]);
+        assert!(waited >= Duration::from_millis(180), "{:?}", waited);
+        assert!(waited < Duration::from_millis(600), "{:?}", waited);
+    }
+
+    #[test]
+    pub fn acquire_without_a_limit_returns_at_once() {
+        let limiter = BandwidthLimiter::new(&None).unwrap();
+
+        assert!(acquire_all(&limiter, vec![1_000_000_000]) < Duration::from_millis(50));
+    }
+
+    #[test]
+    pub fn invalid_time_is_rejected() {
+        assert!(BandwidthLimiter::new(&Some(BandwidthLimit {
+            bytes_per_second: None,
+            schedule: Some(vec![BandwidthWindow {
+                from: "9am".to_owned(),
+                to: "17:00".to_owned(),
+                bytes_per_second: None,
+            }]),
+        })).is_err());
+    }
+}
@@ -1,16 +1,80 @@
-use crate::util;
-use crate::error::CustomResult;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
+use log::{error, info, warn};
+use serde_json::Value;
+
+use crate::config_check::{self, ConfigIssue};
+use crate::config_sources::{self, ConfigSources};
+use crate::error::{CustomError, CustomResult};
+use crate::filenames::TargetFs;
+use crate::app_storage::DownloadOrder;
+use crate::bandwidth::BandwidthLimit;
+use crate::storage_backend::StorageConfig;
+use crate::destinations::DestinationConfig;
+use crate::dedup::DedupMode;
+use crate::dashboard::{DashboardConfig, HttpConfig};
+use crate::health::HealthConfig;
+use crate::logging::LogFormat;
+use crate::notify::NotifyConfig;
+
+// Every field may be left out, see `config check` for the defaults in use
 #[derive(Deserialize, Debug)]
 pub struct Config {
+    #[serde(default = "default_refresh_token_schedule")]
     pub refresh_token_schedule: String,
+    #[serde(default = "default_search_new_items_schedule")]
     pub search_new_items_schedule: String,
+    #[serde(default = "default_download_photos_schedule")]
     pub download_photos_schedule: String,
+    #[serde(default = "default_search_days_back")]
     pub search_days_back: i32,
+    #[serde(default = "default_search_limit")]
     pub search_limit: usize,
-    pub download_files_parallel: i32,
+    // scheduled searches go back to the last successful one instead of search_days_back
+    pub adaptive_search: Option<bool>,
+    pub search_overlap_days: Option<i32>,
+    // searches the whole library, with adaptive_search on
+    pub deep_scan_schedule: Option<String>,
+    // before download_files_per_run it was the number of files per run, see get_download_files_parallel
+    pub download_files_parallel: Option<i32>,
+    pub download_files_per_run: Option<i32>,
+    pub download_batch_size: Option<i32>,
+    pub bandwidth_limit: Option<BandwidthLimit>,
+    pub min_free_space_bytes: Option<u64>,
+    pub max_library_size_bytes: Option<u64>,
+    pub temp_file_max_age_hours: Option<u64>,
+    #[serde(default = "default_storage_location")]
     pub storage_location: String,
-    pub fix_downloaded_info: FixMarkDownloadedInfo
+    pub storage: Option<StorageConfig>,
+    pub destinations: Option<Vec<DestinationConfig>>,
+    pub dedup: Option<DedupMode>,
+    pub duplicates_max_distance: Option<u32>,
+    pub gallery_dir: Option<String>,
+    pub thumbnail_size: Option<u32>,
+    #[serde(default)]
+    pub fix_downloaded_info: FixMarkDownloadedInfo,
+    pub target_fs: Option<TargetFs>,
+    pub download_order: Option<Vec<DownloadOrder>>,
+    pub priority_albums: Option<Vec<String>>,
+    pub pipelined_search: Option<bool>,
+    pub profile: Option<String>,
+    pub http: Option<HttpConfig>,
+    // deprecated, see get_http
+    pub dashboard: Option<DashboardConfig>,
+    pub health: Option<HealthConfig>,
+    pub notify: Option<NotifyConfig>,
+    // run jobs whose schedule fired while the daemon was down once on startup
+    pub catch_up_missed_jobs: Option<bool>,
+    pub history_max_runs: Option<usize>,
+    // defaults to the directory of the executable
+    pub log_dir: Option<String>,
+    pub log_level: Option<String>,
+    pub log_format: Option<LogFormat>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -19,10 +83,279 @@ pub struct FixMarkDownloadedInfo {
     pub unmark_downloaded: bool
 }
 
+impl Default for FixMarkDownloadedInfo {
+    fn default() -> Self {
+        FixMarkDownloadedInfo { mark_downloaded: true, unmark_downloaded: true }
+    }
+}
+
+fn default_refresh_token_schedule() -> String {
+    "0/30 * * * * *".to_owned()
+}
+
+fn default_search_new_items_schedule() -> String {
+    "0 0/20 * * * *".to_owned()
+}
+
+fn default_download_photos_schedule() -> String {
+    "0 0/5 * * * *".to_owned()
+}
+
+fn default_search_days_back() -> i32 {
+    10
+}
+
+fn default_search_limit() -> usize {
+    100000
+}
+
+fn default_storage_location() -> String {
+    "google/photos".to_owned()
+}
+
 impl Config {
-    pub fn new() -> CustomResult<Config> {
-        let path = "config.json";
+    // merges and validates the layers without making the result current
+    pub fn load(sources: &ConfigSources) -> CustomResult<(Config, Value)> {
+        let merged = sources.merge()?;
+        let name = sources.describe();
+        let with_source = |issue: &ConfigIssue| config_check::with_source(issue, &merged);
+
+        let (config, unknown) = config_check::parse(&merged.raw)
+            .map_err(|issue| CustomError::Err(format!("{} {}", name, with_source(&issue))))?;
+
+        for field in unknown {
+            warn!("{} unknown field {} ({}), ignored", name, field, merged.source_of(&field));
+        }
+        for message in config_check::deprecated(&config) {
+            warn!("{} {}", name, message);
+        }
+
+        let issues = config_check::check(&config);
+        if !issues.is_empty() {
+            let issues = issues.iter().map(with_source).collect::<Vec<_>>();
+            return Err(CustomError::Err(format!("{} {}", name, issues.join(", "))));
+        }
+
+        Ok((config, merged.raw))
+    }
+
+    pub fn get_adaptive_search(&self) -> bool {
+        self.adaptive_search.unwrap_or(true)
+    }
+
+    pub fn get_search_overlap_days(&self) -> i32 {
+        self.search_overlap_days.unwrap_or(2).max(0)
+    }
+
+    pub fn get_deep_scan_schedule(&self) -> String {
+        self.deep_scan_schedule.clone().unwrap_or_else(|| "0 0 4 * * Sun".to_owned())
+    }
+
+    // a config with only download_files_parallel keeps its old meaning, the number of files per run
+    pub fn is_old_download_files_parallel(&self) -> bool {
+        self.download_files_parallel.is_some() && self.download_files_per_run.is_none()
+    }
+
+    pub fn get_download_files_parallel(&self) -> i32 {
+        match self.is_old_download_files_parallel() {
+            true => 5,
+            false => self.download_files_parallel.unwrap_or(5).max(1),
+        }
+    }
+
+    pub fn get_download_files_per_run(&self) -> i32 {
+        self.download_files_per_run.or(self.download_files_parallel).unwrap_or(50)
+    }
+
+    pub fn get_download_batch_size(&self) -> i32 {
+        self.download_batch_size.unwrap_or(50).max(1)
+    }
+
+    pub fn get_min_free_space_bytes(&self) -> u64 {
+        self.min_free_space_bytes.unwrap_or(1024 * 1024 * 1024)
+    }
+
+    pub fn get_temp_file_max_age_hours(&self) -> u64 {
+        self.temp_file_max_age_hours.unwrap_or(24)
+    }
+
+    pub fn get_duplicates_max_distance(&self) -> u32 {
+        self.duplicates_max_distance.unwrap_or(8)
+    }
+
+    pub fn get_gallery_dir(&self) -> String {
+        self.gallery_dir.clone().unwrap_or_else(|| "gallery".to_owned())
+    }
+
+    pub fn get_thumbnail_size(&self) -> u32 {
+        self.thumbnail_size.unwrap_or(256).max(16)
+    }
+
+    // names this instance in metrics
+    pub fn get_profile(&self) -> String {
+        self.profile.clone().unwrap_or_else(|| "default".to_owned())
+    }
+
+    pub fn get_http(&self) -> HttpConfig {
+        match (self.http.as_ref(), self.dashboard.as_ref()) {
+            (Some(http), _) => http.clone(),
+            (None, Some(dashboard)) => dashboard.clone().into(),
+            (None, None) => HttpConfig::default(),
+        }
+    }
+
+    pub fn get_health(&self) -> HealthConfig {
+        self.health.clone().unwrap_or_default()
+    }
+
+    pub fn get_catch_up_missed_jobs(&self) -> bool {
+        self.catch_up_missed_jobs.unwrap_or(true)
+    }
+
+    pub fn get_history_max_runs(&self) -> usize {
+        self.history_max_runs.unwrap_or(1000)
+    }
+
+    pub fn get_notify(&self) -> NotifyConfig {
+        self.notify.clone().unwrap_or_default()
+    }
+
+    // a level like "debug" or a spec like "info,rs_google_photos_sync::downloader=debug"
+    pub fn get_log_level(&self) -> String {
+        self.log_level.clone().unwrap_or_else(|| "info".to_owned())
+    }
+
+    pub fn get_download_order(&self) -> Vec<DownloadOrder> {
+        self.download_order.clone().unwrap_or_else(|| vec![DownloadOrder::NewestFirst])
+    }
+}
+
+// these are only read at startup
+const RESTART_KEYS: [&str; 9] = ["storage_location", "storage", "destinations", "target_fs", "http", "dashboard", "log_dir", "log_level", "log_format"];
+
+struct Snapshot {
+    version: u64,
+    config: Arc<Config>,
+    raw: Value,
+}
+
+static SNAPSHOT: RwLock<Option<Snapshot>> = RwLock::new(None);
+static SOURCES: RwLock<Option<ConfigSources>> = RwLock::new(None);
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// The config in use, replaced as a whole by a reload. A task keeps the snapshot it started with.
+pub fn current() -> Arc<Config> {
+    if let Some(snapshot) = SNAPSHOT.read().unwrap().as_ref() {
+        return snapshot.config.clone();
+    }
+
+    init(sources()).unwrap_or_else(|e| panic!("cannot load the config {}", e))
+}
+
+pub fn version() -> u64 {
+    SNAPSHOT.read().unwrap().as_ref().map_or(0, |snapshot| snapshot.version)
+}
+
+// the layers given on the command line, without any the config file is found as before
+pub fn sources() -> ConfigSources {
+    SOURCES.read().unwrap().clone().unwrap_or_else(|| ConfigSources { file: config_sources::default_file(), cli: Vec::new() })
+}
+
+pub fn init(sources: ConfigSources) -> CustomResult<Arc<Config>> {
+    let (config, raw) = Config::load(&sources)?;
+    let config = Arc::new(config);
+    *SNAPSHOT.write().unwrap() = Some(Snapshot { version: 1, config: config.clone(), raw });
+    *SOURCES.write().unwrap() = Some(sources);
+
+    Ok(config)
+}
+
+// An invalid config is rejected and the current one kept
+pub fn reload(sources: &ConfigSources) -> CustomResult<u64> {
+    reload_into(&SNAPSHOT, sources)
+}
+
+fn reload_into(snapshot: &RwLock<Option<Snapshot>>, sources: &ConfigSources) -> CustomResult<u64> {
+    let (config, raw) = Config::load(sources)?;
+    let mut snapshot = snapshot.write().unwrap();
+    let version = snapshot.as_ref().map_or(0, |snapshot| snapshot.version) + 1;
+
+    if let Some(previous) = snapshot.as_ref() {
+        for key in RESTART_KEYS.iter().filter(|key| previous.raw.get(**key) != raw.get(**key)) {
+            warn!("{} changed, it takes effect after a restart", key);
+        }
+    }
+
+    *snapshot = Some(Snapshot { version, config: Arc::new(config), raw });
+
+    Ok(version)
+}
+
+// Reloads when the config file is modified or on SIGHUP, environment variables and flags stay as they were
+pub fn watch_for_changes() {
+    let sources = sources();
+    let name = sources.describe();
+    let modified_at = |file: Option<&PathBuf>| file.and_then(|file| fs::metadata(file).and_then(|meta| meta.modified()).ok());
+    let mut last_modified: Option<SystemTime> = modified_at(sources.file.as_ref());
+
+    listen_for_sighup();
+
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(2));
+
+        let modified = modified_at(sources.file.as_ref());
+        if modified == last_modified && !RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+            continue;
+        }
+        last_modified = modified;
+
+        match reload(&sources) {
+            Ok(version) => info!("{} reloaded, version {}", name, version),
+            Err(e) => error!("{} rejected, keeping version {}: {}", name, version(), e),
+        }
+    });
+}
+
+#[cfg(unix)]
+fn listen_for_sighup() {
+    extern "C" fn on_sighup(_: libc::c_int) {
+        RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+    }
+
+    unsafe {
+        libc::signal(libc::SIGHUP, on_sighup as *const () as libc::sighandler_t);
+    }
+}
+
+#[cfg(not(unix))]
+fn listen_for_sighup() {}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    pub fn reload_rejects_an_invalid_config_and_keeps_the_current_one() {
+        let path = std::env::temp_dir().join("rs-google-photos-sync-config.json");
+        let sources = ConfigSources { file: Some(path.clone()), cli: Vec::new() };
+        let snapshot = RwLock::new(None);
+        let search_limit = |snapshot: &RwLock<Option<Snapshot>>| snapshot.read().unwrap().as_ref().unwrap().config.search_limit;
+        let mut raw = json!({
+            "storage_location": std::env::temp_dir().join("rs-google-photos-sync-photos").to_str().unwrap(),
+            "search_limit": 1234,
+        });
+
+        fs::write(&path, raw.to_string()).unwrap();
+        let version = reload_into(&snapshot, &sources).unwrap();
+        assert_eq!(search_limit(&snapshot), 1234);
 
-        util::read_json_file::<Config>(path.to_owned())
+        raw["search_limit"] = 4321.into();
+        raw["download_photos_schedule"] = "every five minutes".into();
+        fs::write(&path, raw.to_string()).unwrap();
+        assert!(reload_into(&snapshot, &sources).is_err());
+        assert_eq!(search_limit(&snapshot), 1234);
+        assert_eq!(snapshot.read().unwrap().as_ref().unwrap().version, version);
     }
 }
@@ -0,0 +1,384 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use chrono::{DateTime, Local, Utc};
+use job_scheduler::Schedule;
+use serde_json::{json, Value};
+
+use crate::bandwidth::BandwidthLimiter;
+use crate::config::Config;
+use crate::error::{CustomError, CustomResult};
+use crate::notify::NotifierKind;
+use crate::storage_backend::StorageConfig;
+use crate::config_sources::{self, ConfigSources, Merged, Source};
+
+// a problem with the value at path, like "http.address" or "notify.notifiers[1].to"
+#[derive(Debug, PartialEq)]
+pub struct ConfigIssue {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+fn issue(path: &str, message: String) -> ConfigIssue {
+    ConfigIssue { path: path.to_owned(), message }
+}
+
+// names the variable or flag a value came from, the file is named already
+pub fn with_source(issue: &ConfigIssue, merged: &Merged) -> String {
+    match merged.source_of(&issue.path) {
+        Source::Default | Source::File(_) => issue.to_string(),
+        source => format!("{} ({})", issue, source),
+    }
+}
+
+// Also returns the paths of fields that are not known, most likely typos
+pub fn parse(raw: &Value) -> Result<(Config, Vec<String>), ConfigIssue> {
+    let mut unknown = Vec::new();
+    // optional fields add a "?" segment to the path
+    let mut on_unknown = |path: serde_ignored::Path| unknown.push(path.to_string().replace(".?", ""));
+    let deserializer = serde_ignored::Deserializer::new(raw.clone(), &mut on_unknown);
+
+    match serde_path_to_error::deserialize::<_, Config>(deserializer) {
+        Ok(config) => Ok((config, unknown)),
+        Err(e) => Err(issue(&e.path().to_string(), e.inner().to_string())),
+    }
+}
+
+pub fn schedules(config: &Config) -> Vec<(&'static str, String)> {
+    let mut schedules = vec![
+        ("refresh_token_schedule", config.refresh_token_schedule.to_owned()),
+        ("search_new_items_schedule", config.search_new_items_schedule.to_owned()),
+        ("download_photos_schedule", config.download_photos_schedule.to_owned()),
+    ];
+
+    if config.get_adaptive_search() {
+        schedules.push(("deep_scan_schedule", config.get_deep_scan_schedule()));
+    }
+    if config.get_notify().has_digest() {
+        schedules.push(("notify.digest_schedule", config.get_notify().get_digest_schedule()));
+    }
+
+    schedules
+}
+
+// everything that would fail once the daemon runs, checked up front on every load
+pub fn check(config: &Config) -> Vec<ConfigIssue> {
+    let mut issues = Vec::new();
+
+    for (path, expression) in schedules(config) {
+        if let Err(e) = expression.parse::<Schedule>() {
+            issues.push(issue(path, format!("invalid cron expression \"{}\" {}", expression, e)));
+        }
+    }
+
+    let at_least = |path: &str, value: i64, min: i64| match value < min {
+        true => Some(issue(path, format!("{} is less than {}", value, min))),
+        false => None,
+    };
+    issues.extend(vec![
+        at_least("search_days_back", config.search_days_back as i64, 1),
+        at_least("search_limit", config.search_limit as i64, 1),
+        config.download_files_parallel.and_then(|parallel| at_least("download_files_parallel", parallel as i64, 1)),
+        at_least("download_files_per_run", config.get_download_files_per_run() as i64, 0),
+        config.download_batch_size.and_then(|size| at_least("download_batch_size", size as i64, 1)),
+        config.search_overlap_days.and_then(|days| at_least("search_overlap_days", days as i64, 0)),
+        config.thumbnail_size.and_then(|size| at_least("thumbnail_size", size as i64, 16)),
+    ].into_iter().flatten());
+
+    if let Err(e) = BandwidthLimiter::new(&config.bandwidth_limit) {
+        issues.push(issue("bandwidth_limit.schedule", e.to_string()));
+    }
+
+    let http = config.get_http();
+    if http.is_enabled() && http.get_address().parse::<SocketAddr>().is_err() {
+        issues.push(issue("http.address", format!("\"{}\" is not an ip:port address", http.get_address())));
+    }
+
+    for (i, notifier) in config.get_notify().get_notifiers().iter().enumerate() {
+        match &notifier.kind {
+            NotifierKind::Webhook { url } if !url.starts_with("http://") && !url.starts_with("https://") =>
+                issues.push(issue(&format!("notify.notifiers[{}].url", i), format!("\"{}\" is not an http(s) url", url))),
+            NotifierKind::Smtp { to, .. } if to.is_empty() =>
+                issues.push(issue(&format!("notify.notifiers[{}].to", i), "no recipients".to_owned())),
+            _ => {}
+        }
+    }
+
+    issues
+}
+
+// fields still read under their old name, logged on every load
+pub fn deprecated(config: &Config) -> Vec<String> {
+    let mut messages = match (config.dashboard.as_ref(), config.http.as_ref()) {
+        (Some(_), None) => vec!["dashboard is deprecated, use http.dashboard and http.address".to_owned()],
+        (Some(_), Some(_)) => vec!["dashboard is deprecated and ignored, http is set".to_owned()],
+        (None, _) => vec![],
+    };
+
+    if config.is_old_download_files_parallel() {
+        messages.push(format!("download_files_parallel without download_files_per_run is read as {} files per run, \
+            set download_files_per_run, download_files_parallel is now the number of files downloaded at the same time",
+                              config.get_download_files_per_run()));
+    }
+
+    messages
+}
+
+// only `config check` looks at the file system, a load or reload does not
+pub fn check_dirs(config: &Config) -> Vec<ConfigIssue> {
+    local_dirs(config)
+        .into_iter()
+        .filter_map(|(path, dir)| check_writable_dir(Path::new(&dir)).err().map(|message| issue(&path, format!("{} {}", dir, message))))
+        .collect()
+}
+
+// directories files are written to
+fn local_dirs(config: &Config) -> Vec<(String, String)> {
+    let mut dirs = Vec::new();
+
+    match config.destinations.as_ref() {
+        Some(destinations) => {
+            for (i, destination) in destinations.iter().enumerate() {
+                if let StorageConfig::Local { path } = &destination.storage {
+                    let dir = path.clone().unwrap_or_else(|| config.storage_location.to_owned());
+                    dirs.push((format!("destinations[{}].path", i), dir));
+                }
+            }
+        }
+        None => match config.storage.as_ref() {
+            Some(StorageConfig::Local { path: Some(path) }) => dirs.push(("storage.path".to_owned(), path.to_owned())),
+            Some(StorageConfig::S3(_)) => {}
+            _ => dirs.push(("storage_location".to_owned(), config.storage_location.to_owned())),
+        },
+    }
+
+    if let Some(log_dir) = config.log_dir.as_ref() {
+        dirs.push(("log_dir".to_owned(), log_dir.to_owned()));
+    }
+
+    dirs
+}
+
+// a directory that does not exist yet is fine when it can be created
+fn check_writable_dir(dir: &Path) -> Result<(), String> {
+    let existing = dir.ancestors()
+        .find(|ancestor| ancestor.as_os_str().is_empty() || ancestor.exists())
+        .ok_or_else(|| "has no existing parent".to_owned())?;
+    let existing = if existing.as_os_str().is_empty() { Path::new(".") } else { existing };
+
+    if !existing.is_dir() {
+        return Err(format!("{} is not a directory", existing.display()));
+    }
+
+    match is_writable(existing) {
+        true => Ok(()),
+        false => Err(format!("{} is not writable", existing.display())),
+    }
+}
+
+// asks for the permission instead of writing a file
+#[cfg(unix)]
+fn is_writable(dir: &Path) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    match CString::new(dir.as_os_str().as_bytes()) {
+        Ok(dir) => unsafe { libc::access(dir.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_writable(dir: &Path) -> bool {
+    std::fs::metadata(dir).is_ok_and(|meta| !meta.permissions().readonly())
+}
+
+// Optional fields and the value used when they are left out
+pub fn defaults(config: &Config) -> Vec<(&'static str, Value)> {
+    vec![
+        ("refresh_token_schedule", json!(config.refresh_token_schedule)),
+        ("search_new_items_schedule", json!(config.search_new_items_schedule)),
+        ("download_photos_schedule", json!(config.download_photos_schedule)),
+        ("search_days_back", json!(config.search_days_back)),
+        ("search_limit", json!(config.search_limit)),
+        ("adaptive_search", json!(config.get_adaptive_search())),
+        ("search_overlap_days", json!(config.get_search_overlap_days())),
+        ("deep_scan_schedule", json!(config.get_deep_scan_schedule())),
+        ("pipelined_search", json!(config.pipelined_search.unwrap_or(false))),
+        ("download_files_parallel", json!(config.get_download_files_parallel())),
+        ("download_files_per_run", json!(config.get_download_files_per_run())),
+        ("download_batch_size", json!(config.get_download_batch_size())),
+        ("download_order", json!(config.get_download_order())),
+        ("min_free_space_bytes", json!(config.get_min_free_space_bytes())),
+        ("temp_file_max_age_hours", json!(config.get_temp_file_max_age_hours())),
+        ("storage_location", json!(config.storage_location)),
+        ("target_fs", json!(config.target_fs.unwrap_or_default())),
+        ("dedup", json!(config.dedup.unwrap_or_default())),
+        ("duplicates_max_distance", json!(config.get_duplicates_max_distance())),
+        ("gallery_dir", json!(config.get_gallery_dir())),
+        ("thumbnail_size", json!(config.get_thumbnail_size())),
+        ("profile", json!(config.get_profile())),
+        ("catch_up_missed_jobs", json!(config.get_catch_up_missed_jobs())),
+        ("history_max_runs", json!(config.get_history_max_runs())),
+        ("log_level", json!(config.get_log_level())),
+        ("log_format", json!(config.log_format.unwrap_or_default())),
+    ]
+}
+
+// What `config check` prints
+pub fn report(path: &str, merged: &Merged, now: DateTime<Utc>) -> (String, bool) {
+    let raw = &merged.raw;
+    let with_source = |issue: &ConfigIssue| with_source(issue, merged);
+    let (config, unknown) = match parse(raw) {
+        Ok(parsed) => parsed,
+        Err(issue) => return (format!("{} is invalid\n  {}\n", path, with_source(&issue)), false),
+    };
+    let mut issues = check(&config);
+    issues.extend(check_dirs(&config));
+    let mut text = String::new();
+
+    match issues.is_empty() {
+        true => text.push_str(&format!("{} is valid\n", path)),
+        false => {
+            text.push_str(&format!("{} is invalid\n", path));
+            for issue in issues.iter() {
+                text.push_str(&format!("  {}\n", with_source(issue)));
+            }
+        }
+    }
+
+    for path in unknown {
+        text.push_str(&format!("  unknown field {} ({}), ignored\n", path, merged.source_of(&path)));
+    }
+    for message in deprecated(&config) {
+        text.push_str(&format!("  {}\n", message));
+    }
+
+    text.push_str("\nschedules, next runs in local time:\n");
+    for (path, expression) in schedules(&config) {
+        let next = match expression.parse::<Schedule>() {
+            Ok(schedule) => schedule.after(&now).take(3)
+                .map(|at| at.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            Err(_) => "invalid".to_owned(),
+        };
+        text.push_str(&format!("  {:<26} {:<18} {}\n", path, expression, next));
+    }
+
+    let defaults = defaults(&config).into_iter().filter(|(key, _)| raw.get(*key).is_none()).collect::<Vec<_>>();
+    text.push_str(&format!("\ndefaults in use:{}\n", if defaults.is_empty() { " none" } else { "" }));
+    for (key, value) in defaults {
+        text.push_str(&format!("  {} = {}\n", key, value));
+    }
+
+    (text, issues.is_empty())
+}
+
+// `config check`, fails when the daemon would not start with these sources
+pub fn run_check(sources: &ConfigSources) -> CustomResult<()> {
+    let name = sources.describe();
+    let merged = sources.merge()?;
+    let (text, ok) = report(&name, &merged, Utc::now());
+    print!("{}", text);
+
+    match ok {
+        true => Ok(()),
+        false => Err(CustomError::Err(format!("{} is invalid", name))),
+    }
+}
+
+// `config show`
+pub fn run_show(sources: &ConfigSources) -> CustomResult<()> {
+    let merged = sources.merge()?;
+    let (config, _) = parse(&merged.raw)
+        .map_err(|issue| CustomError::Err(format!("{} {}", sources.describe(), with_source(&issue, &merged))))?;
+    print!("{}", config_sources::show(sources, &merged, defaults(&config)));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    pub fn issues_carry_the_path_of_the_field() {
+        let parsed = parse(&json!({ "search_days_back": "ten" }));
+        assert_eq!(parsed.err().map(|issue| issue.path), Some("search_days_back".to_owned()));
+
+        let parsed = parse(&json!({ "http": { "adress": "0.0.0.0:80" }, "notify": { "notifiers": [{ "type": "pager" }] } }));
+        assert_eq!(parsed.err().map(|issue| issue.path), Some("notify.notifiers[0]".to_owned()));
+
+        let raw = json!({
+            "search_days_back": -3,
+            "download_photos_schedule": "every 5 minutes",
+            "http": { "metrics": true, "adress": "0.0.0.0:80", "address": "localhost" },
+            "notify": { "notifiers": [{ "type": "webhook", "url": "example.com/hook" }] }
+        });
+        let (config, unknown) = parse(&raw).unwrap();
+        assert_eq!(unknown, vec!["http.adress"]);
+
+        let issues = check(&config).into_iter().map(|issue| issue.path).collect::<Vec<_>>();
+        assert_eq!(issues, vec!["download_photos_schedule", "search_days_back", "http.address", "notify.notifiers[0].url"]);
+
+        let photos = std::env::temp_dir().join("rs-google-photos-sync-check").join("photos");
+        let merged = Merged { raw: json!({ "storage_location": photos.to_str().unwrap() }), sources: Default::default() };
+        let (text, ok) = report("config.json", &merged, Utc.with_ymd_and_hms(2020, 5, 1, 12, 0, 0).unwrap());
+        assert!(ok, "{}", text);
+        assert!(text.contains("  download_files_per_run = 50\n"));
+        assert!(text.contains("download_photos_schedule   0 0/5 * * * *"));
+    }
+
+    #[test]
+    pub fn the_old_dashboard_field_still_configures_the_http_server() {
+        let (config, unknown) = parse(&json!({ "dashboard": { "enabled": true, "address": "0.0.0.0:3002" } })).unwrap();
+        let http = config.get_http();
+        assert!(unknown.is_empty());
+        assert_eq!((http.dashboard, http.get_address().as_str()), (Some(true), "0.0.0.0:3002"));
+        assert_eq!(deprecated(&config), vec!["dashboard is deprecated, use http.dashboard and http.address"]);
+
+        let (config, _) = parse(&json!({ "dashboard": { "enabled": true }, "http": { "metrics": true } })).unwrap();
+        assert_eq!(config.get_http().dashboard, None);
+        assert_eq!(deprecated(&config).len(), 1);
+    }
+
+    #[test]
+    pub fn an_old_download_files_parallel_is_the_number_of_files_per_run() {
+        let (config, _) = parse(&json!({ "download_files_parallel": 10 })).unwrap();
+        assert_eq!((config.get_download_files_per_run(), config.get_download_files_parallel()), (10, 5));
+        assert_eq!(deprecated(&config).len(), 1);
+
+        let (config, _) = parse(&json!({ "download_files_parallel": 10, "download_files_per_run": 200 })).unwrap();
+        assert_eq!((config.get_download_files_per_run(), config.get_download_files_parallel()), (200, 10));
+        assert!(deprecated(&config).is_empty());
+    }
+
+    #[test]
+    pub fn only_config_check_looks_at_the_directories() {
+        let file = std::env::temp_dir().join("rs-google-photos-sync-not-a-dir");
+        std::fs::write(&file, b"").unwrap();
+        let raw = json!({ "storage_location": file.join("photos").to_str().unwrap() });
+        let (config, _) = parse(&raw).unwrap();
+
+        assert!(check(&config).is_empty());
+        let issues = check_dirs(&config);
+        assert_eq!(issues.iter().map(|issue| issue.path.as_str()).collect::<Vec<_>>(), vec!["storage_location"]);
+        assert!(issues[0].message.ends_with("is not a directory"));
+
+        let merged = Merged { raw, sources: Default::default() };
+        let (text, ok) = report("config.json", &merged, Utc::now());
+        assert!(!ok);
+        assert!(text.contains("  storage_location: "));
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::{Map, Value};
+
+use crate::error::{CustomError, CustomResult};
+
+const APP_NAME: &str = "rs-google-photos-sync";
+const ENV_PREFIX: &str = "RSGPS_";
+const FILE_NAMES: [&str; 4] = ["config.json", "config.toml", "config.yaml", "config.yml"];
+// left out of `config show`
+const SECRET_FIELDS: [&str; 3] = ["password", "token", "secret_key"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    File(String),
+    Env(String),
+    Cli(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path) => write!(f, "file {}", path),
+            Source::Env(name) => write!(f, "env {}", name),
+            Source::Cli(flag) => write!(f, "cli {}", flag),
+        }
+    }
+}
+
+// Where the config comes from, lowest first: the defaults, a file, RSGPS_* environment variables
+// and CLI flags. Kept for the life of the process so a reload merges the same layers again.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSources {
+    // --config, otherwise found by default_file
+    pub file: Option<PathBuf>,
+    // (field path, value, flag) from --set and the flags that set a field
+    pub cli: Vec<(String, Value, String)>,
+}
+
+// the merged config with the source of every value set by a layer, fields left out use their defaults
+#[derive(Debug)]
+pub struct Merged {
+    pub raw: Value,
+    pub sources: BTreeMap<String, Source>,
+}
+
+impl Merged {
+    // of the value at path or of the closest parent that was set as a whole
+    pub fn source_of(&self, path: &str) -> Source {
+        let mut path = path;
+        loop {
+            if let Some(source) = self.sources.get(path) {
+                return source.clone();
+            }
+            match path.rfind(['.', '[']) {
+                Some(i) => path = &path[..i],
+                None => return Source::Default,
+            }
+        }
+    }
+}
+
+impl ConfigSources {
+    // set is a list of field=value, a field path like http.address and a value read as in RSGPS_*
+    pub fn from_args(config: Option<String>, set: Vec<String>, target_fs: Option<String>) -> CustomResult<ConfigSources> {
+        let file = match config {
+            Some(path) => Some(PathBuf::from(path)),
+            None => default_file(),
+        };
+
+        let mut cli = Vec::new();
+        for assignment in set {
+            let (path, value) = assignment.split_once('=')
+                .ok_or_else(|| CustomError::Err(format!("--set {} is not field=value", assignment)))?;
+            cli.push((path.trim().to_owned(), parse_value(value), "--set".to_owned()));
+        }
+        if let Some(target_fs) = target_fs {
+            cli.push(("target_fs".to_owned(), Value::String(target_fs), "--target_fs".to_owned()));
+        }
+
+        Ok(ConfigSources { file, cli })
+    }
+
+    pub fn describe(&self) -> String {
+        match self.file.as_ref() {
+            Some(file) => file.display().to_string(),
+            None => "config".to_owned(),
+        }
+    }
+
+    pub fn merge(&self) -> CustomResult<Merged> {
+        self.merge_with_env(env::vars())
+    }
+
+    fn merge_with_env(&self, vars: impl Iterator<Item=(String, String)>) -> CustomResult<Merged> {
+        let mut merged = Merged { raw: Value::Object(Map::new()), sources: BTreeMap::new() };
+
+        if let Some(file) = self.file.as_ref() {
+            let source = Source::File(file.display().to_string());
+            match read_file(file)? {
+                Value::Object(fields) => {
+                    for (key, value) in fields {
+                        set(&mut merged, &key, value, &source)?;
+                    }
+                }
+                _ => return Err(CustomError::Err(format!("{} is not an object of fields", file.display()))),
+            }
+        }
+
+        let mut vars = vars.filter(|(name, _)| name.starts_with(ENV_PREFIX)).collect::<Vec<_>>();
+        vars.sort();
+        for (name, value) in vars {
+            // RSGPS_HTTP__ADDRESS is http.address, RSGPS_NOTIFY__NOTIFIERS__0__TOKEN notify.notifiers[0].token
+            let path = name[ENV_PREFIX.len()..].to_lowercase().replace("__", ".");
+            set(&mut merged, &path, parse_value(&value), &Source::Env(name.to_owned()))
+                .map_err(|e| CustomError::Err(format!("{} {}", name, e)))?;
+        }
+
+        for (path, value, flag) in self.cli.iter() {
+            set(&mut merged, path, value.clone(), &Source::Cli(flag.to_owned()))
+                .map_err(|e| CustomError::Err(format!("{} {}", flag, e)))?;
+        }
+
+        Ok(merged)
+    }
+}
+
+// config.json in the working directory as before, otherwise config.{json,toml,yaml,yml}
+// in $XDG_CONFIG_HOME/rs-google-photos-sync (~/.config when not set)
+pub fn default_file() -> Option<PathBuf> {
+    if Path::new(FILE_NAMES[0]).exists() {
+        return Some(PathBuf::from(FILE_NAMES[0]));
+    }
+
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+
+    FILE_NAMES.iter()
+        .map(|name| config_home.join(APP_NAME).join(name))
+        .find(|path| path.exists())
+}
+
+// the format follows the extension, JSON when there is none
+pub fn read_file(path: &Path) -> CustomResult<Value> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| CustomError::Err(format!("cannot read {} {}", path.display(), e)))?;
+    let invalid = |e: String| CustomError::Err(format!("{} is invalid {}", path.display(), e));
+
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => toml::from_str::<Value>(&text).map_err(|e| invalid(e.to_string())),
+        Some("yaml") | Some("yml") => serde_yaml::from_str::<Value>(&text).map_err(|e| invalid(e.to_string())),
+        _ => serde_json::from_str::<Value>(&text).map_err(|e| invalid(e.to_string())),
+    }
+}
+
+// JSON when it parses, so 10, true and ["a","b"] keep their type, otherwise the text as a string
+fn parse_value(text: &str) -> Value {
+    serde_json::from_str::<Value>(text).unwrap_or_else(|_| Value::String(text.to_owned()))
+}
+
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+// http.address, notify.notifiers[0].token or notify.notifiers.0.token
+fn parse_path(path: &str) -> CustomResult<Vec<Segment>> {
+    let invalid = || CustomError::Err(format!("invalid field path {}", path));
+    let mut segments = Vec::new();
+
+    for part in path.split('.') {
+        let (key, mut indexes) = part.split_at(part.find('[').unwrap_or(part.len()));
+        match key.parse::<usize>() {
+            Ok(index) => segments.push(Segment::Index(index)),
+            Err(_) if !key.is_empty() => segments.push(Segment::Key(key.to_owned())),
+            Err(_) => return Err(invalid()),
+        }
+
+        while !indexes.is_empty() {
+            let (index, rest) = indexes.strip_prefix('[').and_then(|rest| rest.split_once(']')).ok_or_else(invalid)?;
+            segments.push(Segment::Index(index.parse().map_err(|_| invalid())?));
+            indexes = rest;
+        }
+    }
+
+    Ok(segments)
+}
+
+fn format_path(segments: &[Segment]) -> String {
+    let mut path = String::new();
+    for segment in segments {
+        match segment {
+            Segment::Key(key) if path.is_empty() => path.push_str(key),
+            Segment::Key(key) => path.push_str(&format!(".{}", key)),
+            Segment::Index(index) => path.push_str(&format!("[{}]", index)),
+        }
+    }
+
+    path
+}
+
+// Sets the value at a field path, objects are merged field by field and anything else replaced.
+// A list element can only be set when the list already has it, a lower layer has to give the list.
+fn set(merged: &mut Merged, path: &str, value: Value, source: &Source) -> CustomResult<()> {
+    let segments = parse_path(path)?;
+    let mut target = &mut merged.raw;
+
+    for (i, segment) in segments.iter().enumerate() {
+        target = match segment {
+            Segment::Key(key) => {
+                if !target.is_object() {
+                    *target = Value::Object(Map::new());
+                }
+                target.as_object_mut().unwrap().entry(key.to_owned()).or_insert(Value::Null)
+            }
+            Segment::Index(index) => match target.as_array_mut().and_then(|items| items.get_mut(*index)) {
+                Some(item) => item,
+                None => return Err(CustomError::Err(format!("{} has no element [{}]", format_path(&segments[..i]), index))),
+            },
+        };
+    }
+
+    merge_into(target, &format_path(&segments), value, source, &mut merged.sources);
+
+    Ok(())
+}
+
+fn merge_into(target: &mut Value, path: &str, value: Value, source: &Source, sources: &mut BTreeMap<String, Source>) {
+    match value {
+        Value::Object(fields) if target.is_object() || target.is_null() => {
+            if target.is_null() {
+                *target = Value::Object(Map::new());
+            }
+            for (key, value) in fields {
+                let child = target.as_object_mut().unwrap().entry(key.to_owned()).or_insert(Value::Null);
+                merge_into(child, &format!("{}.{}", path, key), value, source, sources);
+            }
+        }
+        value => {
+            sources.retain(|set_path, _| !is_within(set_path, path));
+            sources.insert(path.to_owned(), source.clone());
+            *target = value;
+        }
+    }
+}
+
+fn is_within(path: &str, parent: &str) -> bool {
+    path == parent || path.starts_with(&format!("{}.", parent)) || path.starts_with(&format!("{}[", parent))
+}
+
+// `config show`, every value with the layer it came from, secrets masked
+pub fn show(sources: &ConfigSources, merged: &Merged, defaults: Vec<(&'static str, Value)>) -> String {
+    let mut values = Vec::new();
+    leaves(&merged.raw, "", &mut values);
+    for (key, value) in defaults.into_iter().filter(|(key, _)| merged.raw.get(*key).is_none()) {
+        values.push((key.to_owned(), value));
+    }
+    values.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut text = format!("config file: {}\n\n", sources.file.as_ref().map_or("none".to_owned(), |file| file.display().to_string()));
+    for (path, value) in values {
+        let secret = path.rsplit(['.', ']']).next().is_some_and(|field| SECRET_FIELDS.contains(&field));
+        let value = if secret && !value.is_null() { "\"********\"".to_owned() } else { value.to_string() };
+        text.push_str(&format!("{:<36} = {:<28} {}\n", path, value, merged.source_of(&path)));
+    }
+
+    text
+}
+
+fn leaves(value: &Value, path: &str, values: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(fields) if !fields.is_empty() => {
+            for (key, value) in fields {
+                let path = if path.is_empty() { key.to_owned() } else { format!("{}.{}", path, key) };
+                leaves(value, &path, values);
+            }
+        }
+        Value::Array(items) if items.iter().any(Value::is_object) => {
+            for (i, item) in items.iter().enumerate() {
+                leaves(item, &format!("{}[{}]", path, i), values);
+            }
+        }
+        value => values.push((path.to_owned(), value.clone())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    pub fn later_layers_override_earlier_ones_field_by_field() {
+        let path = env::temp_dir().join("rs-google-photos-sync-layers.toml");
+        fs::write(&path, "search_limit = 100\nstorage_location = \"/photos\"\n\n[http]\naddress = \"127.0.0.1:3002\"\nmetrics = true\n\n[notify]\nnotifiers = [{ type = \"gotify\", server = \"https://gotify\", token = \"abc\" }]\n").unwrap();
+
+        let sources = ConfigSources::from_args(
+            Some(path.to_str().unwrap().to_owned()),
+            vec!["http.address=0.0.0.0:3002".to_owned(), "profile=\"123\"".to_owned()],
+            Some("windows".to_owned()),
+        ).unwrap();
+        let vars = vec![
+            ("RSGPS_SEARCH_LIMIT".to_owned(), "20".to_owned()),
+            ("RSGPS_HTTP__ADDRESS".to_owned(), "0.0.0.0:80".to_owned()),
+            ("RSGPS_PRIORITY_ALBUMS".to_owned(), "[\"a\",\"b\"]".to_owned()),
+            ("HOME".to_owned(), "/root".to_owned()),
+        ];
+        let merged = sources.merge_with_env(vars.into_iter()).unwrap();
+
+        assert_eq!(merged.raw, json!({
+            "search_limit": 20,
+            "storage_location": "/photos",
+            "http": { "address": "0.0.0.0:3002", "metrics": true },
+            "notify": { "notifiers": [{ "type": "gotify", "server": "https://gotify", "token": "abc" }] },
+            "priority_albums": ["a", "b"],
+            "profile": "123",
+            "target_fs": "windows",
+        }));
+        assert_eq!(merged.source_of("search_limit"), Source::Env("RSGPS_SEARCH_LIMIT".to_owned()));
+        assert_eq!(merged.source_of("http.address"), Source::Cli("--set".to_owned()));
+        assert_eq!(merged.source_of("http.metrics"), Source::File(path.display().to_string()));
+        assert_eq!(merged.source_of("notify.notifiers[0].token"), Source::File(path.display().to_string()));
+        assert_eq!(merged.source_of("search_days_back"), Source::Default);
+
+        let text = show(&sources, &merged, vec![("search_days_back", json!(10))]);
+        assert!(text.contains("search_days_back                     = 10                           default\n"));
+        assert!(text.contains("target_fs                            = \"windows\"                    cli --target_fs\n"));
+        assert!(text.contains("notify.notifiers[0].token            = \"********\""));
+    }
+
+    #[test]
+    pub fn list_elements_are_set_by_index() {
+        let path = env::temp_dir().join("rs-google-photos-sync-list-elements.json");
+        fs::write(&path, r#"{ "destinations": [{ "storage": { "type": "local" } }], "notify": { "notifiers": [{ "type": "ntfy" }] } }"#).unwrap();
+
+        let sources = ConfigSources::from_args(
+            Some(path.to_str().unwrap().to_owned()),
+            vec!["destinations[0].storage.path=/mnt/usb".to_owned()],
+            None,
+        ).unwrap();
+        let vars = vec![("RSGPS_NOTIFY__NOTIFIERS__0__TOKEN".to_owned(), "abc".to_owned())];
+        let merged = sources.merge_with_env(vars.into_iter()).unwrap();
+
+        assert_eq!(merged.raw["destinations"][0]["storage"], json!({ "type": "local", "path": "/mnt/usb" }));
+        assert_eq!(merged.raw["notify"]["notifiers"][0], json!({ "type": "ntfy", "token": "abc" }));
+        assert_eq!(merged.source_of("notify.notifiers[0].token"), Source::Env("RSGPS_NOTIFY__NOTIFIERS__0__TOKEN".to_owned()));
+        assert_eq!(merged.source_of("notify.notifiers[0].type"), Source::File(path.display().to_string()));
+
+        let sources = ConfigSources { cli: vec![("notify.notifiers[1].token".to_owned(), json!("abc"), "--set".to_owned())], ..sources };
+        let e = sources.merge_with_env(std::iter::empty()).err().unwrap();
+        assert_eq!(e.to_string(), "--set notify.notifiers has no element [1]");
+        assert!(ConfigSources { cli: vec![("a[x]".to_owned(), json!(1), "--set".to_owned())], ..sources }
+            .merge_with_env(std::iter::empty()).is_err());
+    }
+}
@@ -0,0 +1,287 @@
+// nickel handlers return its own large error type
+#![allow(clippy::result_large_err)]
+
+use std::sync::Mutex;
+use std::sync::mpsc::Sender;
+
+use chrono::{DateTime, Utc};
+use nickel::{HttpRouter, MediaType, MiddlewareResult, Nickel, Options, Request, Response};
+use nickel::extensions::Redirect;
+use nickel::status::StatusCode;
+use log::info;
+
+use crate::config::{self, Config};
+use crate::error::{CustomError, CustomResult};
+use crate::health::{self, HealthReport};
+use crate::metrics::METRICS;
+use crate::scheduling::{self, JobTask};
+use crate::status::{SharedStatus, Status};
+use crate::util;
+
+// The http server is started when any of its pages is enabled
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct HttpConfig {
+    // defaults to 127.0.0.1:3002
+    pub address: Option<String>,
+    // anyone who can reach the dashboard can start tasks
+    pub dashboard: Option<bool>,
+    pub metrics: Option<bool>,
+    // /healthz and /readyz
+    pub health: Option<bool>,
+}
+
+impl HttpConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.dashboard.unwrap_or(false) || self.metrics.unwrap_or(false) || self.health.unwrap_or(false)
+    }
+
+    pub fn get_address(&self) -> String {
+        self.address.clone().unwrap_or_else(|| "127.0.0.1:3002".to_owned())
+    }
+}
+
+// "dashboard" before it became part of "http", still read when "http" is left out
+#[derive(Deserialize, Debug, Clone)]
+pub struct DashboardConfig {
+    pub enabled: bool,
+    pub address: Option<String>,
+}
+
+impl From<DashboardConfig> for HttpConfig {
+    fn from(dashboard: DashboardConfig) -> HttpConfig {
+        HttpConfig { address: dashboard.address, dashboard: Some(dashboard.enabled), ..HttpConfig::default() }
+    }
+}
+
+struct Dashboard {
+    status: SharedStatus,
+    tx: Mutex<Sender<JobTask>>,
+    address: String,
+}
+
+pub fn start_http_server(http: &HttpConfig, status: SharedStatus, tx: Sender<JobTask>) -> CustomResult<()> {
+    let address = http.get_address();
+    let dashboard = Dashboard { status, tx: Mutex::new(tx), address: address.clone() };
+    let mut server = Nickel::with_data_and_options(dashboard, Options::default().output_on_listen(false));
+
+    if http.dashboard.unwrap_or(false) {
+        server.get("/", index);
+        server.get("/api/status", status_json);
+        server.post("/tasks/search", search);
+        server.post("/tasks/download", download);
+    }
+
+    if http.metrics.unwrap_or(false) {
+        server.get("/metrics", metrics);
+    }
+
+    if http.health.unwrap_or(false) {
+        server.get("/healthz", healthz);
+        server.get("/readyz", readyz);
+    }
+
+    let listener = server.listen(address.as_str())
+        .map_err(|e| CustomError::Err(format!("cannot start http server on {} {}", address, e)))?;
+    info!("http server on http://{}", listener.socket());
+    listener.detach();
+
+    Ok(())
+}
+
+fn index<'mw>(req: &mut Request<Dashboard>, res: Response<'mw, Dashboard>) -> MiddlewareResult<'mw, Dashboard> {
+    let next_runs = scheduling::next_runs(&config::current()).unwrap_or_default();
+    let html = render(&req.server_data().status.lock().unwrap(), &next_runs, Utc::now());
+
+    res.send(html)
+}
+
+fn status_json<'mw>(req: &mut Request<Dashboard>, mut res: Response<'mw, Dashboard>) -> MiddlewareResult<'mw, Dashboard> {
+    let json = serde_json::to_string_pretty(&*req.server_data().status.lock().unwrap()).unwrap_or_default();
+    res.set(MediaType::Json);
+
+    res.send(json)
+}
+
+fn metrics<'mw>(req: &mut Request<Dashboard>, mut res: Response<'mw, Dashboard>) -> MiddlewareResult<'mw, Dashboard> {
+    let profile = config::current().get_profile();
+    let text = METRICS.render(&profile, &req.server_data().status.lock().unwrap(), Utc::now());
+    res.set(MediaType::Txt);
+
+    res.send(text)
+}
+
+fn healthz<'mw>(req: &mut Request<Dashboard>, res: Response<'mw, Dashboard>) -> MiddlewareResult<'mw, Dashboard> {
+    let config = config::current().get_health();
+    let report = health::liveness(&req.server_data().status.lock().unwrap(), &config, Utc::now());
+
+    send_health(res, &report)
+}
+
+fn readyz<'mw>(req: &mut Request<Dashboard>, res: Response<'mw, Dashboard>) -> MiddlewareResult<'mw, Dashboard> {
+    let config = config::current();
+    let next_runs = scheduling::next_runs(&config).unwrap_or_default();
+    // the weekly deep scan would always be older than max_success_age_minutes
+    let tasks = next_runs.iter().map(|(task, _)| *task).filter(|task| *task != JobTask::DeepScanTask.name()).collect::<Vec<_>>();
+    let report = health::readiness(&req.server_data().status.lock().unwrap(), &config.get_health(), &tasks, Utc::now());
+
+    send_health(res, &report)
+}
+
+fn send_health<'mw>(mut res: Response<'mw, Dashboard>, report: &HealthReport) -> MiddlewareResult<'mw, Dashboard> {
+    res.set(MediaType::Json);
+    res.set(if report.ok { StatusCode::Ok } else { StatusCode::ServiceUnavailable });
+
+    res.send(serde_json::to_string_pretty(report).unwrap_or_default())
+}
+
+fn search<'mw>(req: &mut Request<Dashboard>, res: Response<'mw, Dashboard>) -> MiddlewareResult<'mw, Dashboard> {
+    send_task(req, res, scheduling::search_task)
+}
+
+fn download<'mw>(req: &mut Request<Dashboard>, res: Response<'mw, Dashboard>) -> MiddlewareResult<'mw, Dashboard> {
+    send_task(req, res, |config| JobTask::DownloadFilesTask(config.get_download_files_per_run()))
+}
+
+// Queues the task, unless one of its type is already pending or running, and goes back to the dashboard.
+// Posts from other sites are refused, a page elsewhere could otherwise submit these forms.
+fn send_task<'mw, F>(req: &mut Request<Dashboard>, mut res: Response<'mw, Dashboard>, task: F) -> MiddlewareResult<'mw, Dashboard>
+    where F: FnOnce(&Config) -> JobTask
+{
+    let header = |name: &str| req.origin.headers.get_raw(name).and_then(|value| String::from_utf8(value[0].clone()).ok());
+
+    if !is_same_site(header("Host"), header("Origin"), header("Referer"), &req.server_data().address) {
+        res.set(StatusCode::Forbidden);
+        return res.send("cross-origin request refused");
+    }
+
+    let task = task(&config::current());
+
+    if req.server_data().tx.lock().unwrap().send(task).is_err() {
+        res.set(StatusCode::ServiceUnavailable);
+        return res.send("task receiver has stopped");
+    }
+
+    res.redirect_with("/", StatusCode::SeeOther)
+}
+
+// The Host must be the configured address or a loopback name, so a rebound DNS name cannot reach the
+// dashboard, and the Origin, or the Referer when a browser leaves it out, must be that same host
+fn is_same_site(host: Option<String>, origin: Option<String>, referer: Option<String>, address: &str) -> bool {
+    let host = match host {
+        Some(host) if host == address || is_loopback(&host) => host,
+        _ => return false,
+    };
+    let host_of = |url: &str| url.split("://").nth(1).map(|rest| rest.split('/').next().unwrap_or_default().to_owned());
+
+    match origin.or(referer) {
+        Some(url) => host_of(&url).as_deref() == Some(host.as_str()),
+        None => false,
+    }
+}
+
+fn is_loopback(host: &str) -> bool {
+    let name = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+
+    name == "localhost" || name == "[::1]" || name.parse::<std::net::Ipv4Addr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+fn render(status: &Status, next_runs: &[(&str, Option<DateTime<Utc>>)], now: DateTime<Utc>) -> String {
+    let time = |at: &DateTime<Utc>| at.format("%Y-%m-%d %H:%M:%S UTC").to_string();
+    let mut html = String::from("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>rs-google-photos-sync</title>\n\
+        <meta http-equiv=\"refresh\" content=\"30\">\n\
+        <style>body{font-family:sans-serif;margin:1em 2em} table{border-collapse:collapse;margin-bottom:1em} \
+        td,th{border:1px solid #ccc;padding:4px 8px;text-align:left} form{display:inline}</style></head><body>\n\
+        <h1>rs-google-photos-sync</h1>\n");
+
+    let totals = &status.totals;
+    html.push_str(&format!("<h2>Catalog</h2>\n<table><tr><th>Searched</th><th>Downloaded</th><th>Pending</th><th>Failed</th></tr>\n\
+        <tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr></table>\n",
+                           totals.searched, totals.downloaded, totals.pending, totals.failed));
+
+    for reason in &status.download_paused {
+        html.push_str(&format!("<p>Download paused: {}</p>\n", util::html_escape(&reason.to_string())));
+    }
+
+    let token = match status.token_expires_at {
+        Some(expires_at) if expires_at > now => format!("valid until {}", time(&expires_at)),
+        Some(expires_at) => format!("expired at {}, renewed by the next RefreshTokenTask", time(&expires_at)),
+        None => "no token".to_owned(),
+    };
+    html.push_str(&format!("<h2>Token</h2>\n<p>{}</p>\n", token));
+
+    html.push_str("<h2>Tasks</h2>\n<table><tr><th>Task</th><th>Next run</th><th>Last success</th></tr>\n");
+    for (task, next_run) in next_runs {
+        html.push_str(&format!("<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n", task,
+                               next_run.as_ref().map(time).unwrap_or_default(),
+                               status.last_success.get(*task).map(time).unwrap_or_default()));
+    }
+    html.push_str("</table>\n");
+
+    let running = status.running.as_deref().unwrap_or("idle");
+    html.push_str(&format!("<p>Running: {}</p>\n\
+        <form method=\"post\" action=\"/tasks/search\"><button>Search now</button></form>\n\
+        <form method=\"post\" action=\"/tasks/download\"><button>Download now</button></form>\n", running));
+
+    html.push_str("<h2>Recent errors</h2>\n");
+    if status.recent_errors.is_empty() {
+        html.push_str("<p>none</p>\n");
+    } else {
+        html.push_str("<table><tr><th>At</th><th>Task</th><th>Error</th></tr>\n");
+        for error in status.recent_errors.iter() {
+            html.push_str(&format!("<tr><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                                   time(&error.at), error.task, util::html_escape(&error.message)));
+        }
+        html.push_str("</table>\n");
+    }
+
+    html.push_str("</body></html>\n");
+    html
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+    use crate::disk_space::PauseReason;
+
+    #[test]
+    pub fn render_shows_totals_next_runs_and_escaped_errors() {
+        let now = Utc.with_ymd_and_hms(2020, 5, 1, 12, 0, 0).unwrap();
+        let mut status = Status::default();
+        status.totals.searched = 12;
+        status.token_expires_at = Some(now - Duration::minutes(5));
+        status.task_finished("SearchFilesTask", &Ok(()));
+        status.record_error("DownloadFilesTask", "bad <response>".to_owned());
+        status.record_pauses(vec![PauseReason::QuotaReached { destination: "usb".to_owned(), library_size: 100, quota: 150 }]);
+
+        let html = render(&status, &[("DownloadFilesTask", Some(now + Duration::hours(1)))], now);
+
+        assert!(html.contains("<td>12</td>"));
+        assert!(html.contains("<td>DownloadFilesTask</td><td>2020-05-01 13:00:00 UTC</td>"));
+        assert!(html.contains("expired at 2020-05-01 11:55:00 UTC"));
+        assert!(html.contains("bad &lt;response&gt;"));
+        assert!(html.contains("<p>Download paused: library size in usb 100 bytes reached quota of 150 bytes</p>"));
+        assert!(html.contains("action=\"/tasks/download\""));
+    }
+
+    #[test]
+    pub fn tasks_are_only_taken_from_the_dashboard_itself() {
+        let same_site = |host: &str, origin: Option<&str>, referer: Option<&str>| {
+            is_same_site(Some(host.to_owned()), origin.map(str::to_owned), referer.map(str::to_owned), "192.168.1.5:3002")
+        };
+
+        assert!(same_site("192.168.1.5:3002", Some("http://192.168.1.5:3002"), None));
+        assert!(same_site("localhost:3002", None, Some("http://localhost:3002/")));
+        assert!(same_site("127.0.0.1:8080", Some("http://127.0.0.1:8080"), None));
+        assert!(same_site("[::1]:3002", Some("http://[::1]:3002"), None));
+
+        assert!(!same_site("192.168.1.5:3002", None, None));
+        assert!(!same_site("192.168.1.5:3002", Some("http://evil.example"), None));
+        assert!(!same_site("evil.example:3002", Some("http://evil.example:3002"), None));
+        assert!(!is_same_site(None, Some("http://localhost:3002".to_owned()), None, "127.0.0.1:3002"));
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use log::{error, info, warn};
+
+use crate::{FileName, MediaItemId, StoredItem, StoredItemStore};
+use crate::destinations::{Destination, DestinationName};
+use crate::error::CustomResult;
+use crate::integrity::{hash_object, FileDigest};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DedupMode {
+    #[default]
+    Off,
+    Hardlink,
+    Reflink,
+}
+
+type ContentKey = (Option<DestinationName>, String);
+
+// sha256 of every stored file per destination, pointing at the copy later duplicates are linked to
+pub struct ContentIndex {
+    mode: DedupMode,
+    files: Mutex<HashMap<ContentKey, (MediaItemId, FileName)>>,
+}
+
+impl ContentIndex {
+    pub fn new(mode: DedupMode, storage: &StoredItemStore, destinations: &[Destination]) -> ContentIndex {
+        let mut files = HashMap::new();
+
+        if mode != DedupMode::Off {
+            for destination in destinations {
+                let replica = destination.replica();
+
+                for stored_item in storage.get_all() {
+                    let info = match stored_item.get_download_info_at(replica) {
+                        Some(info) if info.linked_to.is_none() => info,
+                        _ => continue,
+                    };
+
+                    if let Some(sha256) = info.sha256.as_ref() {
+                        files.entry((replica.map(str::to_owned), sha256.to_owned()))
+                            .or_insert_with(|| (stored_item.mediaItem.id.to_owned(), stored_item.get_filename()));
+                    }
+                }
+            }
+        }
+
+        ContentIndex { mode, files: Mutex::new(files) }
+    }
+
+    fn enabled_for(&self, destination: &Destination) -> bool {
+        self.mode != DedupMode::Off && destination.backend.local_root().is_some()
+    }
+
+    // the earlier copy with the same content, or None after recording this one as the first copy
+    fn find_or_insert(&self, destination: &Destination, stored_item: &StoredItem, sha256: &str) -> Option<(MediaItemId, FileName)> {
+        let key = (destination.replica().map(str::to_owned), sha256.to_owned());
+        let mut files = self.files.lock().unwrap();
+
+        match files.get(&key) {
+            Some((id, filename)) if *id != stored_item.mediaItem.id => Some((id.to_owned(), filename.to_owned())),
+            Some(_) => None,
+            None => {
+                files.insert(key, (stored_item.mediaItem.id.to_owned(), stored_item.get_filename()));
+                None
+            }
+        }
+    }
+
+    pub fn find(&self, destination: &Destination, stored_item: &StoredItem, digest: &FileDigest) -> Option<(MediaItemId, FileName)> {
+        if !self.enabled_for(destination) {
+            return None;
+        }
+
+        let files = self.files.lock().unwrap();
+        let key = (destination.replica().map(str::to_owned), digest.sha256.clone()?);
+
+        files.get(&key)
+            .filter(|(id, _)| *id != stored_item.mediaItem.id)
+            .cloned()
+    }
+
+    // Replaces the file of stored_item at destination with a link to an earlier file with the same content.
+    // Returns the media item id of that file when linked.
+    pub async fn link_duplicate(&self, destination: &Destination, stored_item: &StoredItem, digest: &FileDigest)
+                                -> Option<MediaItemId>
+    {
+        if !self.enabled_for(destination) {
+            return None;
+        }
+
+        let sha256 = digest.sha256.as_ref()?;
+        let (id, existing) = self.find_or_insert(destination, stored_item, sha256)?;
+
+        self.link(destination, stored_item, &id, &existing, sha256).await
+    }
+
+    // the existing file is hashed again first, it is only linked to while it still has the content sha256 stands for
+    pub async fn link(&self, destination: &Destination, stored_item: &StoredItem, id: &MediaItemId, existing: &str, sha256: &str)
+                      -> Option<MediaItemId>
+    {
+        let filename = stored_item.get_filename();
+        let reflink = self.mode == DedupMode::Reflink;
+
+        if !has_content(destination, existing, sha256).await {
+            return None;
+        }
+
+        match destination.backend.link(existing, &filename, reflink).await {
+            Ok(true) => {
+                // a hardlink shares the mtime of the first copy, a reflink is a file of its own
+                if reflink {
+                    let mtime = stored_item.mediaItem.mediaMetadata.creationTime;
+                    if let Err(e) = destination.backend.set_mtime(&filename, mtime).await {
+                        error!("Error setting mtime of {} {}", filename, e);
+                    }
+                }
+                info!("linked {} to {} in {}", filename, existing, destination.name);
+                Some(id.to_owned())
+            }
+            Ok(false) => None,
+            Err(e) => {
+                error!("Error linking {} in {} {}", filename, destination.name, e);
+                None
+            }
+        }
+    }
+}
+
+// a recorded sha256 goes stale when the file is edited or replaced in the library after it was hashed
+async fn has_content(destination: &Destination, name: &str, sha256: &str) -> bool {
+    match hash_object(&*destination.backend, name).await {
+        Ok(digest) if digest.sha256.as_deref() == Some(sha256) => true,
+        Ok(_) => {
+            warn!("{} in {} changed since it was hashed, not linked", name, destination.name);
+            false
+        }
+        Err(e) => {
+            error!("Error hashing {} in {} {}", name, destination.name, e);
+            false
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DedupReport {
+    pub linked: Vec<(MediaItemId, MediaItemId)>,
+    pub bytes_saved: u64,
+}
+
+// Links the files that are already in the library. Only files with a recorded sha256 take part,
+// --verify hashes the ones downloaded before hashes were recorded. Both files are hashed again before
+// they are linked, a file that no longer matches its sha256 is left alone.
+pub async fn dedup_library(storage: &mut StoredItemStore, destination: &Destination, mode: DedupMode)
+                           -> CustomResult<DedupReport>
+{
+    let mut report = DedupReport::default();
+    let index = ContentIndex { mode, files: Mutex::new(HashMap::new()) };
+
+    if !index.enabled_for(destination) {
+        return Ok(report);
+    }
+
+    let replica = destination.replica();
+
+    let mut stored_items = storage.get_all()
+        .into_iter()
+        .filter(|stored_item| stored_item.get_download_info_at(replica).is_some_and(|info| info.sha256.is_some()))
+        .collect::<Vec<_>>();
+
+    // the oldest item keeps its own file
+    stored_items.sort_by(|a, b| {
+        a.creation_time().cmp(&b.creation_time()).then_with(|| a.mediaItem.id.cmp(&b.mediaItem.id))
+    });
+
+    for stored_item in stored_items {
+        let info = stored_item.get_download_info_at(replica).unwrap();
+        let sha256 = info.sha256.as_ref().unwrap();
+
+        if let Some((id, existing)) = index.find_or_insert(destination, stored_item, sha256) {
+            if info.linked_to.as_ref() == Some(&id) {
+                continue;
+            }
+
+            if !has_content(destination, &stored_item.get_filename(), sha256).await {
+                continue;
+            }
+
+            if let Some(linked_to) = index.link(destination, stored_item, &id, &existing, sha256).await {
+                report.bytes_saved += info.size.unwrap_or(0);
+                report.linked.push((stored_item.mediaItem.id.to_owned(), linked_to));
+            }
+        }
+    }
+
+    for (id, linked_to) in report.linked.iter() {
+        if let Some(mut stored_item) = storage.get_cloned(id) {
+            stored_item.mark_linked_at(replica, linked_to);
+            storage.set(id, stored_item);
+        }
+    }
+
+    storage.persist()?;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::integrity::DigestBuilder;
+    use crate::storage_backend::LocalBackend;
+    use crate::test::StoredItemBuilder;
+
+    fn add(storage: &mut StoredItemStore, id: &str, created_day: u32, content: &[u8]) {
+        let mut digest = DigestBuilder::new();
+        digest.update(content);
+
+        StoredItemBuilder::new(id, &format!("{}.jpg", id))
+            .created_on(created_day)
+            .downloaded(Some(4), digest.finish().sha256.as_deref())
+            .add_to(storage);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn identical_files_are_hardlinked_to_the_oldest() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = std::env::temp_dir().join("rs-google-photos-sync-dedup");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut storage = StoredItemStore::new(dir.join("data").to_str().unwrap());
+        add(&mut storage, "copy", 2, b"same");
+        add(&mut storage, "original", 1, b"same");
+        add(&mut storage, "other", 3, b"diff");
+        // hashed as a copy, edited in the library since
+        add(&mut storage, "edited", 4, b"same");
+
+        fs::write(dir.join("copy.jpg"), b"same").unwrap();
+        fs::write(dir.join("original.jpg"), b"same").unwrap();
+        fs::write(dir.join("other.jpg"), b"diff").unwrap();
+        fs::write(dir.join("edited.jpg"), b"edit").unwrap();
+
+        let destination = Destination {
+            name: "primary".to_owned(),
+            primary: true,
+            backend: Box::new(LocalBackend::new(dir.to_str().unwrap())),
+        };
+
+        let dedup = dedup_library(&mut storage, &destination, DedupMode::Hardlink);
+        let report = tokio::runtime::Runtime::new().unwrap().block_on(dedup).unwrap();
+
+        assert_eq!(report.linked, vec![("copy".to_owned(), "original".to_owned())]);
+        assert_eq!(report.bytes_saved, 4);
+
+        let inode = |name: &str| fs::metadata(dir.join(name)).unwrap().ino();
+        assert_eq!(inode("copy.jpg"), inode("original.jpg"));
+        assert_ne!(inode("other.jpg"), inode("original.jpg"));
+        assert_ne!(inode("edited.jpg"), inode("original.jpg"));
+        assert_eq!(fs::read(dir.join("edited.jpg")).unwrap(), b"edit");
+
+        let copy = storage.get(&"copy".to_owned()).unwrap();
+        assert_eq!(copy.get_download_info().unwrap().linked_to, Some("original".to_owned()));
+    }
+}
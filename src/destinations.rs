@@ -0,0 +1,60 @@
+use std::collections::HashSet;
+
+use crate::config::Config;
+use crate::error::{CustomError, CustomResult};
+use crate::storage_backend::{self, StorageBackend, StorageConfig};
+
+pub type DestinationName = String;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DestinationConfig {
+    pub name: DestinationName,
+    #[serde(flatten)]
+    pub storage: StorageConfig,
+}
+
+pub struct Destination {
+    pub name: DestinationName,
+    pub primary: bool,
+    pub backend: Box<dyn StorageBackend>,
+}
+
+impl Destination {
+    // the catalog keeps the primary copy in download_info and the other copies under their
+    // destination name in replicas, so a catalog from before destinations keeps working
+    pub fn replica(&self) -> Option<&str> {
+        if self.primary { None } else { Some(self.name.as_str()) }
+    }
+}
+
+// without destinations in config.json there is a single primary destination from storage or storage_location
+pub fn create_destinations(config: &Config, client: &reqwest::Client) -> CustomResult<Vec<Destination>> {
+    let configs = match config.destinations.as_ref() {
+        Some(destinations) if !destinations.is_empty() => destinations.clone(),
+        _ => vec![DestinationConfig {
+            name: "primary".to_owned(),
+            storage: config.storage.clone().unwrap_or(StorageConfig::Local { path: None }),
+        }],
+    };
+
+    let mut names = HashSet::new();
+    let mut destinations = Vec::with_capacity(configs.len());
+
+    for (i, destination) in configs.iter().enumerate() {
+        if !names.insert(destination.name.as_str()) {
+            return Err(CustomError::Err(format!("duplicate destination name {}", destination.name)));
+        }
+
+        destinations.push(Destination {
+            name: destination.name.to_owned(),
+            primary: i == 0,
+            backend: storage_backend::create_backend(&destination.storage, config, client)?,
+        });
+    }
+
+    Ok(destinations)
+}
+
+pub fn replica_names(destinations: &[Destination]) -> Vec<&str> {
+    destinations.iter().filter_map(Destination::replica).collect()
+}
@@ -0,0 +1,197 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::StoredItemStore;
+use crate::app_storage::AppStorage;
+use crate::config::Config;
+use crate::destinations::{Destination, DestinationName};
+use crate::error::CustomError;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum PauseReason {
+    LowDiskSpace { destination: DestinationName, available: u64, reserve: u64 },
+    QuotaReached { destination: DestinationName, library_size: u64, quota: u64 },
+}
+
+impl PauseReason {
+    pub fn destination(&self) -> &str {
+        match self {
+            PauseReason::LowDiskSpace { destination, .. } | PauseReason::QuotaReached { destination, .. } => destination,
+        }
+    }
+}
+
+impl fmt::Display for PauseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PauseReason::LowDiskSpace { destination, available, reserve } =>
+                write!(f, "low disk space in {}, {} bytes available, {} bytes reserved", destination, available, reserve),
+            PauseReason::QuotaReached { destination, library_size, quota } =>
+                write!(f, "library size in {} {} bytes reached quota of {} bytes", destination, library_size, quota),
+        }
+    }
+}
+
+impl From<PauseReason> for CustomError {
+    fn from(reason: PauseReason) -> Self {
+        CustomError::Err(format!("download paused: {}", reason))
+    }
+}
+
+// every destination holds the whole library, so each is checked on its own
+struct Library {
+    name: DestinationName,
+    replica: Option<DestinationName>,
+    // None for a destination that is not on a local disk, only the quota applies to it
+    dir: Option<PathBuf>,
+    size: AtomicU64,
+    paused: Mutex<Option<PauseReason>>,
+}
+
+// Checked before every file written to a destination. Once the free space reserve or the library quota
+// is hit, that destination stays paused for the rest of the run so the files left for it are skipped
+// instead of failing one by one. The other destinations keep downloading.
+pub struct SpaceGuard {
+    libraries: Vec<Library>,
+    reserve: u64,
+    quota: Option<u64>,
+}
+
+impl SpaceGuard {
+    pub fn new(config: &Config, destinations: &[Destination], storage: &StoredItemStore) -> SpaceGuard {
+        SpaceGuard {
+            libraries: destinations
+                .iter()
+                .map(|destination| Library {
+                    name: destination.name.to_owned(),
+                    replica: destination.replica().map(str::to_owned),
+                    dir: destination.backend.local_root().map(Path::to_path_buf),
+                    size: AtomicU64::new(storage.downloaded_size(destination.replica())),
+                    paused: Mutex::new(None),
+                })
+                .collect(),
+            reserve: config.get_min_free_space_bytes(),
+            quota: config.max_library_size_bytes,
+        }
+    }
+
+    pub fn paused(&self, replica: Option<&str>) -> Option<PauseReason> {
+        self.library(replica).and_then(|library| library.paused.lock().unwrap().clone())
+    }
+
+    // nothing more can be written anywhere, the run stops
+    pub fn all_paused(&self) -> bool {
+        self.libraries.iter().all(|library| library.paused.lock().unwrap().is_some())
+    }
+
+    pub fn pauses(&self) -> Vec<PauseReason> {
+        self.libraries.iter().filter_map(|library| library.paused.lock().unwrap().clone()).collect()
+    }
+
+    // before a run, with nothing incoming yet
+    pub fn check_all(&self) {
+        for library in &self.libraries {
+            let _ = self.check_library(library, 0);
+        }
+    }
+
+    // incoming is the size of the file about to be written to the destination, 0 when not known
+    pub fn check(&self, replica: Option<&str>, incoming: u64) -> Result<(), PauseReason> {
+        match self.library(replica) {
+            Some(library) => self.check_library(library, incoming),
+            None => Ok(()),
+        }
+    }
+
+    fn check_library(&self, library: &Library, incoming: u64) -> Result<(), PauseReason> {
+        if let Some(reason) = library.paused.lock().unwrap().clone() {
+            return Err(reason);
+        }
+
+        let library_size = library.size.load(Ordering::SeqCst);
+
+        let available = library.dir.as_ref().and_then(|dir| fs2::available_space(dir).ok());
+
+        let reason = match available {
+            Some(available) if available < self.reserve + incoming => {
+                Some(PauseReason::LowDiskSpace { destination: library.name.to_owned(), available, reserve: self.reserve })
+            }
+            _ => match self.quota {
+                Some(quota) if library_size + incoming > quota => {
+                    Some(PauseReason::QuotaReached { destination: library.name.to_owned(), library_size, quota })
+                }
+                _ => None,
+            },
+        };
+
+        match reason {
+            Some(reason) => {
+                *library.paused.lock().unwrap() = Some(reason.clone());
+                Err(reason)
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn add_downloaded(&self, replica: Option<&str>, bytes: u64) {
+        if let Some(library) = self.library(replica) {
+            library.size.fetch_add(bytes, Ordering::SeqCst);
+        }
+    }
+
+    fn library(&self, replica: Option<&str>) -> Option<&Library> {
+        self.libraries.iter().find(|library| library.replica.as_deref() == replica)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage_backend::LocalBackend;
+    use crate::test::StoredItemBuilder;
+
+    fn guard(config: serde_json::Value, storage: &StoredItemStore) -> SpaceGuard {
+        let dir = std::env::temp_dir();
+        let destinations = ["primary", "usb"].iter()
+            .map(|name| Destination {
+                name: name.to_string(),
+                primary: *name == "primary",
+                backend: Box::new(LocalBackend::new(dir.to_str().unwrap())),
+            })
+            .collect::<Vec<_>>();
+
+        SpaceGuard::new(&serde_json::from_value(config).unwrap(), &destinations, storage)
+    }
+
+    #[test]
+    pub fn every_destination_is_held_to_the_quota() {
+        let path = std::env::temp_dir().join("rs-google-photos-sync-space-guard.data");
+        let _ = std::fs::remove_file(&path);
+        let mut storage = StoredItemStore::new(path.to_str().unwrap());
+        StoredItemBuilder::new("a", "a.jpg").downloaded(Some(100), None).add_to(&mut storage);
+
+        let space = guard(serde_json::json!({ "min_free_space_bytes": 0, "max_library_size_bytes": 150 }), &storage);
+        assert!(space.check(Some("usb"), 100).is_ok());
+        assert!(matches!(space.check(None, 60), Err(PauseReason::QuotaReached { library_size: 100, .. })));
+
+        let space = guard(serde_json::json!({ "min_free_space_bytes": 0, "max_library_size_bytes": 150 }), &storage);
+        space.add_downloaded(Some("usb"), 100);
+        let reason = space.check(Some("usb"), 60).unwrap_err();
+        assert_eq!(reason.to_string(), "library size in usb 100 bytes reached quota of 150 bytes");
+        assert_eq!(space.paused(Some("usb")), Some(reason.clone()));
+        assert_eq!(space.pauses(), vec![reason]);
+
+        // a full usb disk does not hold up the primary library
+        assert!(space.paused(None).is_none());
+        assert!(space.check(None, 40).is_ok());
+        assert!(!space.all_paused());
+
+        let space = guard(serde_json::json!({ "min_free_space_bytes": u64::MAX / 2 }), &storage);
+        space.check_all();
+        assert!(matches!(space.paused(None), Some(PauseReason::LowDiskSpace { .. })));
+        assert!(space.all_paused());
+    }
+}
@@ -1,63 +1,214 @@
-use std::fs;
-use std::fs::File;
-use std::io::copy;
-use std::path::Path;
-use std::sync::mpsc;
-
-use scoped_threadpool::Pool;
+use futures::StreamExt;
+use futures::stream;
+use reqwest::{header, Client, Response, StatusCode};
+use log::{error, info, warn};
 
 use crate::{MediaItemId, StoredItem};
 use crate::error::{CustomResult, CustomError};
-use filetime::FileTime;
 use crate::config::Config;
+use crate::integrity::{DigestBuilder, FileDigest};
+use crate::bandwidth::BandwidthLimiter;
+use crate::disk_space::SpaceGuard;
+use crate::storage_backend::StorageBackend;
+use crate::destinations::{Destination, DestinationName};
+use crate::dedup::ContentIndex;
+use crate::metrics::METRICS;
 
-pub fn download(stored_items: &Vec<StoredItem>) -> CustomResult<Vec<MediaItemId>> {
-    let config = Config::new()?;
-    fs::create_dir_all(&config.storage_location)
-        .map_err(|e| CustomError::Err(
-            format!("error creating dir all {} {}", &config.storage_location, e)
-        ))?;
-
-    let group_size = 5;
-    let mut pool = Pool::new(group_size);
-
-    let (tx, rx) = mpsc::channel();
-    let config = Config::new()?;
-    let dest_dir= config.storage_location.as_str();
-
-    pool.scoped(|scoped| {
-        for stored_item in stored_items {
-            let tx = tx.clone();
-            scoped.execute(move || {
-                let res = stored_item.download(dest_dir);
-
-                match res {
-                    Ok(_) => tx.send(Some(stored_item.mediaItem.id.to_owned())).unwrap(),
-                    Err(e) => {
-                        println!("Error downloading {} {:#?}", stored_item.get_filename(), e);
-                        tx.send(None).unwrap();
-                    }
-                }
-            });
+pub struct DownloadedCopy {
+    pub id: MediaItemId,
+    pub replica: Option<DestinationName>,
+    pub digest: FileDigest,
+    pub linked_to: Option<MediaItemId>,
+}
+
+pub struct Downloads {
+    pub copies: Vec<DownloadedCopy>,
+    // left for a later run, every destination still missing them is paused
+    pub skipped: Vec<MediaItemId>,
+}
+
+// Each item is fetched from Google once, into the first destination that is missing it, and then
+// copied from a destination that has it to the remaining ones. With dedup on, a copy whose content is
+// already stored at a destination is replaced by a link to that file. A paused destination is passed
+// over, an item that only paused destinations are missing is skipped. The pauses are reported once the
+// run stops.
+pub async fn download(client: &Client, config: &Config, bandwidth: &BandwidthLimiter, space: &SpaceGuard, destinations: &[Destination],
+                      index: &ContentIndex, stored_items: &Vec<StoredItem>) -> CustomResult<Downloads>
+{
+    for destination in destinations {
+        destination.backend.prepare().await?;
+    }
+
+    let group_size = config.get_download_files_parallel() as usize;
+
+    let stored = stream::iter(stored_items)
+        .map(|stored_item| async move {
+            let copies = match space.all_paused() {
+                true => None,
+                false => store_item(stored_item, client, bandwidth, space, destinations, index).await,
+            };
+
+            (&stored_item.mediaItem.id, copies)
+        })
+        .buffer_unordered(group_size)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut downloads = Downloads { copies: Vec::new(), skipped: Vec::new() };
+    for (id, copies) in stored {
+        match copies {
+            Some(copies) => downloads.copies.extend(copies),
+            None => downloads.skipped.push(id.to_owned()),
+        }
+    }
+
+    Ok(downloads)
+}
+
+// None when the item was skipped
+async fn store_item(stored_item: &StoredItem, client: &Client, bandwidth: &BandwidthLimiter, space: &SpaceGuard,
+                    destinations: &[Destination], index: &ContentIndex) -> Option<Vec<DownloadedCopy>>
+{
+    let id = &stored_item.mediaItem.id;
+    let filename = stored_item.get_filename();
+    let copy = |destination: &Destination, digest: FileDigest, linked_to: Option<MediaItemId>| {
+        if linked_to.is_none() {
+            space.add_downloaded(destination.replica(), digest.size);
         }
+
+        DownloadedCopy {
+            id: id.to_owned(),
+            replica: destination.replica().map(str::to_owned),
+            digest,
+            linked_to,
+        }
+    };
+
+    let (mut missing, present): (Vec<_>, Vec<_>) = destinations
+        .iter()
+        .partition(|destination| stored_item.get_download_info_at(destination.replica()).is_none());
+
+    if missing.is_empty() {
+        return Some(Vec::new());
+    }
+
+    let mut copies = Vec::new();
+    let mut errored = false;
+
+    let source = present.first().and_then(|destination| {
+        stored_item.get_download_info_at(destination.replica()).map(|info| (*destination, FileDigest {
+            size: info.size.unwrap_or(0),
+            sha256: info.sha256.clone(),
+        }))
     });
 
-    let vec = rx.into_iter()
-        .take(stored_items.len())
-        .filter(|value| {
-            match value {
-                Some(_) => true,
-                None => false
+    let (source, digest) = match source {
+        Some(source) => source,
+        None => loop {
+            let destination = match missing.iter().position(|destination| space.paused(destination.replica()).is_none()) {
+                Some(i) => missing.remove(i),
+                None => return None,
+            };
+
+            match stored_item.download(client, bandwidth, space, destination).await {
+                Ok(digest) => {
+                    let linked_to = index.link_duplicate(destination, stored_item, &digest).await;
+                    copies.push(copy(destination, digest.clone(), linked_to));
+                    break (destination, digest);
+                }
+                // the destination has no room for it, the next one may
+                Err(_) if space.paused(destination.replica()).is_some() => continue,
+                Err(e) => {
+                    error!("Error downloading {} {:#?}", filename, e);
+                    return Some(copies);
+                }
             }
-        })
-        .collect::<Option<Vec<_>>>();
+        },
+    };
+
+    for destination in missing {
+        let duplicate = index.find(destination, stored_item, &digest);
+        if let (Some((linked_id, existing)), Some(sha256)) = (duplicate, digest.sha256.as_deref()) {
+            if let Some(linked_to) = index.link(destination, stored_item, &linked_id, &existing, sha256).await {
+                copies.push(copy(destination, digest.clone(), Some(linked_to)));
+                continue;
+            }
+        }
+
+        if space.check(destination.replica(), digest.size).is_err() {
+            continue;
+        }
+
+        match replicate(stored_item, &*source.backend, &*destination.backend, &digest).await {
+            Ok(digest) => {
+                let linked_to = index.link_duplicate(destination, stored_item, &digest).await;
+                copies.push(copy(destination, digest, linked_to));
+            }
+            Err(e) => {
+                METRICS.download_error("replicate");
+                error!("Error copying {} from {} to {} {:#?}", filename, source.name, destination.name, e);
+                errored = true;
+            }
+        }
+    }
+
+    // nothing was stored and nothing failed, every destination left was paused
+    match copies.is_empty() && !errored {
+        true => None,
+        false => Some(copies),
+    }
+}
+
+async fn replicate(stored_item: &StoredItem, from: &dyn StorageBackend, to: &dyn StorageBackend, expected: &FileDigest)
+                   -> CustomResult<FileDigest>
+{
+    let filename = stored_item.get_filename();
+    let put_name = put_name(to, &filename);
+
+    // sizes recorded before sizes were tracked are 0, the stored object knows better
+    let size = match expected.size {
+        0 => from.stat(&filename).await?.map(|info| info.size),
+        size => Some(size),
+    };
+
+    info!("copying {} to {}", filename, to.describe());
+
+    let mut digest = DigestBuilder::new();
+    let body = from.get(&filename).await?
+        .inspect(|chunk| if let Ok(chunk) = chunk { digest.update(chunk) })
+        .boxed();
+
+    if let Err(e) = to.put(&put_name, 0, size, stored_item.mediaItem.mediaMetadata.creationTime, body).await {
+        let _ = to.remove(&put_name).await;
+        return Err(e);
+    }
 
-    Ok(vec.or_else(|| Some(Vec::new())).unwrap())
+    let digest = digest.finish();
+
+    if expected.sha256.is_some() && digest.sha256 != expected.sha256 {
+        let _ = to.remove(&put_name).await;
+        return Err(CustomError::Err(format!("copy of {} does not match the recorded sha256", filename)));
+    }
+
+    if put_name != filename {
+        to.rename(&put_name, &filename).await?;
+    }
+
+    Ok(digest)
+}
+
+// a .tmp renamed once complete, unless the backend never shows a partial object
+fn put_name(backend: &dyn StorageBackend, filename: &str) -> String {
+    match backend.atomic_put() {
+        true => filename.to_owned(),
+        false => format!("{}.tmp", filename),
+    }
 }
 
 
 trait Download {
-    fn download(&self, dest_dir: &str) -> CustomResult<()>;
+    async fn download(&self, client: &Client, bandwidth: &BandwidthLimiter, space: &SpaceGuard, destination: &Destination)
+                      -> CustomResult<FileDigest>;
 }
 
 pub trait DownloadUrl {
@@ -65,28 +216,189 @@ pub trait DownloadUrl {
 }
 
 impl Download for StoredItem {
-    fn download(&self, dest_dir: &str) -> CustomResult<()> {
+    async fn download(&self, client: &Client, bandwidth: &BandwidthLimiter, space: &SpaceGuard, destination: &Destination)
+                      -> CustomResult<FileDigest>
+    {
+        let backend = &*destination.backend;
         let filename = self.get_filename();
 
         let url = self.mediaItem.create_download_url()?;
-        let path = Path::new(dest_dir).join(format!("{}.tmp", &filename));
+        let put_name = put_name(backend, &filename);
+
+        // a .tmp left by an interrupted run is continued with a range request when the server allows it
+        let mut existing_size = match backend.supports_append() {
+            true => backend.stat(&put_name).await.map_err(failed("storage"))?.map(|info| info.size).unwrap_or(0),
+            false => 0,
+        };
 
-        {
-            let mut dest = File::create(&path)?;
+        let mut resp = request_download(client, &url, existing_size).await?;
 
-            let mut resp = reqwest::get(url.as_str())?;
-            println!("downloading {}", filename);
-            let _ = copy(&mut resp, &mut dest)?;
+        // the .tmp is as long as the file or longer, it is not a part of it
+        if existing_size > 0 && resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            warn!("cannot resume {} from {} bytes, downloading it again", filename, existing_size);
+            backend.remove(&put_name).await.map_err(failed("storage"))?;
+            existing_size = 0;
+            resp = request_download(client, &url, existing_size).await?;
         }
 
-        let rename_to = Path::new(dest_dir).join(&filename);
-        std::fs::rename(path, &rename_to)?;
+        let resp = resp.error_for_status().map_err(failed("http"))?;
+        let resuming = existing_size > 0 && resp.status() == StatusCode::PARTIAL_CONTENT;
+        let incoming_size = resp.content_length();
+        let expected_size = incoming_size.map(|size| if resuming { size + existing_size } else { size });
+        space.check(destination.replica(), incoming_size.unwrap_or(0)).map_err(failed("disk_space"))?;
+
+        let mut digest = DigestBuilder::new();
 
-        filetime::set_file_mtime(&rename_to, FileTime::from_unix_time(
-            self.mediaItem.mediaMetadata.creationTime.timestamp(), 0
-        ))?;
+        if resuming {
+            info!("resuming {} from {} bytes", filename, existing_size);
+
+            let mut existing = backend.get(&put_name).await.map_err(failed("storage"))?;
+            while let Some(chunk) = existing.next().await {
+                digest.update(&chunk.map_err(failed("storage"))?);
+            }
+        } else {
+            info!("downloading {}", filename);
+        }
 
-        Ok(())
+        let mut network_failed = false;
+
+        let body = resp.bytes_stream()
+            .map(|chunk| chunk.map_err(CustomError::from))
+            .then(|chunk| async move {
+                if let Ok(chunk) = chunk.as_ref() {
+                    bandwidth.acquire(chunk.len()).await;
+                }
+                chunk
+            })
+            .inspect(|chunk| match chunk {
+                Ok(chunk) => digest.update(chunk),
+                Err(_) => network_failed = true,
+            })
+            .boxed();
+
+        let offset = if resuming { existing_size } else { 0 };
+        let res = backend.put(&put_name, offset, incoming_size, self.mediaItem.mediaMetadata.creationTime, body).await;
+
+        let res = res
+            .map_err(failed(if network_failed { "network" } else { "storage" }))
+            .and_then(|_| {
+                let digest = digest.finish();
+
+                match expected_size {
+                    Some(expected) if expected != digest.size => Err(failed("truncated")(CustomError::Err(
+                        format!("truncated download {} {}/{} bytes", filename, digest.size, expected)
+                    ))),
+                    _ => Ok(digest),
+                }
+            });
+
+        // a failed write, e.g. on a full disk, must not leave a partial .tmp behind,
+        // a dropped connection leaves it to be resumed
+        let digest = match res {
+            Ok(digest) => digest,
+            Err(e) => {
+                if !network_failed {
+                    let _ = backend.remove(&put_name).await;
+                }
+                return Err(e);
+            }
+        };
+
+        if put_name != filename {
+            backend.rename(&put_name, &filename).await.map_err(failed("storage"))?;
+        }
+        METRICS.add_downloaded_bytes(digest.size - offset);
+
+        Ok(digest)
+    }
+}
+
+async fn request_download(client: &Client, url: &str, from: u64) -> CustomResult<Response> {
+    let mut request = client.get(url);
+    if from > 0 {
+        request = request.header(header::RANGE, format!("bytes={}-", from));
+    }
+
+    let resp = request.send().await;
+    METRICS.api_call("download", resp.as_ref().ok().map(|resp| resp.status()));
+
+    resp.map_err(failed("network"))
+}
+
+// counts a failed download by kind before passing the error on
+fn failed<E: Into<CustomError>>(kind: &'static str) -> impl FnOnce(E) -> CustomError {
+    move |e| {
+        METRICS.download_error(kind);
+        e.into()
     }
 }
 
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::storage_backend::LocalBackend;
+    use crate::test::StoredItemBuilder;
+
+    // answers a range request with 416 and any other with the whole file
+    #[test]
+    pub fn a_temp_file_that_cannot_be_resumed_is_downloaded_again() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = thread::spawn(move || {
+            let mut ranges = Vec::new();
+
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                let mut range = None;
+                let mut line = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    if line.to_lowercase().starts_with("range:") {
+                        range = Some(line.trim().to_owned());
+                    }
+                    line.clear();
+                }
+
+                let response: &[u8] = match range {
+                    Some(_) => b"HTTP/1.1 416 Range Not Satisfiable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    None => b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nabc",
+                };
+                stream.write_all(response).unwrap();
+                ranges.push(range);
+            }
+
+            ranges
+        });
+
+        let dir = std::env::temp_dir().join("rs-google-photos-sync-resume-416");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.jpg.tmp"), b"a stale part").unwrap();
+
+        let mut stored_item = StoredItemBuilder::new("a", "a.jpg").build();
+        stored_item.mediaItem.baseUrl = format!("http://127.0.0.1:{}/a", port);
+
+        let config: Config = serde_json::from_value(serde_json::json!({})).unwrap();
+        let destination = Destination {
+            name: "primary".to_owned(),
+            primary: true,
+            backend: Box::new(LocalBackend::new(dir.to_str().unwrap())),
+        };
+        let storage = crate::StoredItemStore::new(dir.join("data").to_str().unwrap());
+        let space = SpaceGuard::new(&config, std::slice::from_ref(&destination), &storage);
+        let (bandwidth, client) = (BandwidthLimiter::new(&None).unwrap(), Client::new());
+        let download = stored_item.download(&client, &bandwidth, &space, &destination);
+        let digest = tokio::runtime::Runtime::new().unwrap().block_on(download).unwrap();
+
+        assert_eq!(digest.size, 3);
+        assert_eq!(fs::read(dir.join("a.jpg")).unwrap(), b"abc");
+        assert!(!dir.join("a.jpg.tmp").exists());
+        assert_eq!(server.join().unwrap(), vec![Some("range: bytes=12-".to_owned()), None]);
+    }
+}
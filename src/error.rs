@@ -84,3 +84,9 @@ impl From<cron::error::Error> for CustomError {
         CustomError::Err(format!("error with cron string {}", e))
     }
 }
+
+impl From<tokio::task::JoinError> for CustomError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        CustomError::Err(format!("error joining task {}", e))
+    }
+}
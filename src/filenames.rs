@@ -0,0 +1,448 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+use crate::{FileName, MediaItemId, StoredItemStore};
+use crate::error::CustomError;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TargetFs {
+    #[default]
+    Posix,
+    Windows,
+    Exfat,
+}
+
+impl FromStr for TargetFs {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "posix" => Ok(TargetFs::Posix),
+            "windows" => Ok(TargetFs::Windows),
+            "exfat" => Ok(TargetFs::Exfat),
+            _ => Err(CustomError::Err(format!("unknown target fs {}, expected posix, windows or exfat", s))),
+        }
+    }
+}
+
+impl TargetFs {
+    pub fn is_case_insensitive(self) -> bool {
+        self != TargetFs::Posix
+    }
+
+    fn is_invalid_char(self, c: char) -> bool {
+        match self {
+            TargetFs::Posix => c == '/' || c == '\0',
+            TargetFs::Windows | TargetFs::Exfat => c < ' ' || "<>:\"/\\|?*".contains(c),
+        }
+    }
+
+    // ext4 counts bytes, NTFS and exFAT count UTF-16 code units
+    fn name_len(self, name: &str) -> usize {
+        match self {
+            TargetFs::Posix => name.len(),
+            TargetFs::Windows | TargetFs::Exfat => name.encode_utf16().count(),
+        }
+    }
+}
+
+const MAX_NAME_LEN: usize = 255;
+
+const WINDOWS_RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// Makes a Google filename usable as a single path component on the target filesystem.
+// The original name stays in mediaItem.filename, the sanitised one goes to alt_filename.
+pub fn sanitize_filename(filename: &str, target_fs: TargetFs) -> FileName {
+    let mut name = filename
+        .chars()
+        .map(|c| if target_fs.is_invalid_char(c) { '_' } else { c })
+        .collect::<String>();
+
+    if target_fs != TargetFs::Posix {
+        let trimmed = name.trim_end_matches(['.', ' ']).len();
+        let trailing = name.len() - trimmed;
+        name.truncate(trimmed);
+        name.push_str(&"_".repeat(trailing));
+    }
+
+    // CON.tar.gz is as reserved as CON.gz
+    if target_fs == TargetFs::Windows {
+        let stem = name.split('.').next().unwrap_or_default();
+        if WINDOWS_RESERVED_NAMES.contains(&stem.to_uppercase().as_str()) {
+            name.insert(0, '_');
+        }
+    }
+
+    if name.is_empty() || name == "." || name == ".." {
+        name = name.replace('.', "_");
+        if name.is_empty() {
+            name.push('_');
+        }
+    }
+
+    let (stem, ext) = split_extension(&name);
+    fit_filename(stem, ext, target_fs)
+}
+
+fn split_extension(filename: &str) -> (&str, &str) {
+    match filename.rfind('.') {
+        Some(dot) if dot > 0 => filename.split_at(dot),
+        _ => (filename, ""),
+    }
+}
+
+// Truncates the stem so the name fits in the filesystem limit, the end of the tail only when it is too long
+// on its own. A suffix at the start of the tail is kept.
+fn fit_filename(stem: &str, tail: &str, target_fs: TargetFs) -> FileName {
+    let mut stem = stem.to_owned();
+    let mut tail = tail.to_owned();
+
+    while !stem.is_empty() && target_fs.name_len(&stem) + target_fs.name_len(&tail) > MAX_NAME_LEN {
+        stem.pop();
+    }
+
+    while target_fs.name_len(&tail) > MAX_NAME_LEN {
+        tail.pop();
+    }
+
+    // a cut stem without a tail, or a cut tail, can end in a dot or space again,
+    // the rule for trailing dots and spaces of sanitize_filename applies once more
+    let mut name = format!("{}{}", stem, tail);
+    if target_fs != TargetFs::Posix {
+        let trimmed = name.trim_end_matches(['.', ' ']).len();
+        let trailing = name.len() - trimmed;
+        name.truncate(trimmed);
+        name.push_str(&"_".repeat(trailing));
+    }
+
+    name
+}
+
+// On case-insensitive filesystems IMG.JPG and img.jpg are the same file, so names are compared folded.
+// The probe is a new file of its own, never one already in the library, and is removed on every path.
+pub fn detect_case_insensitive_fs(dir: &Path) -> bool {
+    let name = format!(".case_probe_{}", std::process::id());
+    let probe = dir.join(&name);
+    let probe_upper = dir.join(name.to_uppercase());
+
+    if fs::OpenOptions::new().write(true).create_new(true).open(&probe).is_err() {
+        return cfg!(any(windows, target_os = "macos"));
+    }
+
+    let insensitive = probe_upper.exists();
+    let _ = fs::remove_file(&probe);
+
+    insensitive
+}
+
+struct Candidate {
+    id: MediaItemId,
+    filename: FileName,
+    alt_filename: Option<FileName>,
+    creation_time: DateTime<Utc>,
+}
+
+// The names of the files already stored, and of the items given a name since
+pub struct TakenNames {
+    names: HashSet<FileName>,
+    fold_case: bool,
+}
+
+impl TakenNames {
+    pub fn of(storage: &StoredItemStore, fold_case: bool) -> TakenNames {
+        let mut taken = TakenNames { names: HashSet::new(), fold_case };
+        for stored_item in storage.get_all().into_iter().filter(|stored_item| stored_item.is_downloaded_anywhere()) {
+            taken.insert(&stored_item.get_filename());
+        }
+
+        taken
+    }
+
+    fn key(&self, name: &str) -> FileName {
+        if self.fold_case { name.to_lowercase() } else { name.to_owned() }
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.names.contains(&self.key(name))
+    }
+
+    fn insert(&mut self, name: &str) {
+        let key = self.key(name);
+        self.names.insert(key);
+    }
+}
+
+// Items already on disk keep their names. Every other item, oldest first and then by id, takes its own
+// sanitised filename if it is still free, otherwise the sanitised filename with a short id suffix
+// (IMG_1234_3fa9c2d1.jpg). Returns the number of items whose name changed.
+pub fn resolve_filenames(storage: &mut StoredItemStore, target_fs: TargetFs, fold_case: bool) -> usize {
+    let mut taken = TakenNames::of(storage, fold_case);
+    let ids = storage.get_all()
+        .into_iter()
+        .filter(|stored_item| !stored_item.is_downloaded_anywhere())
+        .map(|stored_item| stored_item.mediaItem.id.to_owned())
+        .collect::<Vec<_>>();
+
+    resolve_filenames_of(storage, &ids, &mut taken, target_fs)
+}
+
+// Only the given items, like a page of search results about to be downloaded, the names they take are
+// added to taken. Names of the other items may still collide with them until the next resolve_filenames.
+pub fn resolve_filenames_of(storage: &mut StoredItemStore, ids: &[MediaItemId], taken: &mut TakenNames, target_fs: TargetFs) -> usize {
+    let mut candidates = ids.iter()
+        .filter_map(|id| storage.get(id))
+        .filter(|stored_item| !stored_item.is_downloaded_anywhere())
+        .map(|stored_item| Candidate {
+            id: stored_item.mediaItem.id.to_owned(),
+            filename: stored_item.mediaItem.filename.to_owned(),
+            alt_filename: stored_item.alt_filename.to_owned(),
+            creation_time: stored_item.mediaItem.mediaMetadata.creationTime,
+        })
+        .collect::<Vec<_>>();
+
+    candidates.sort_by(|a, b| {
+        a.creation_time.cmp(&b.creation_time).then_with(|| a.id.cmp(&b.id))
+    });
+
+    let mut renamed = 0;
+
+    for candidate in candidates {
+        let sanitized = sanitize_filename(&candidate.filename, target_fs);
+        let name = std::iter::once(sanitized.to_owned())
+            .chain(suffixed_filenames(&sanitized, &candidate.id, target_fs))
+            .find(|name| !taken.contains(name))
+            .unwrap();
+
+        taken.insert(&name);
+
+        let alt_filename = if name == candidate.filename { None } else { Some(name) };
+
+        if alt_filename != candidate.alt_filename {
+            if let Some(mut item) = storage.get_cloned(&candidate.id) {
+                item.alt_filename = alt_filename;
+                storage.set(&candidate.id, item);
+                renamed += 1;
+            }
+        }
+    }
+
+    renamed
+}
+
+fn suffixed_filenames<'a>(filename: &'a str, id: &MediaItemId, target_fs: TargetFs)
+                          -> impl Iterator<Item = FileName> + 'a
+{
+    let short_id = format!("{:x}", Sha256::digest(id.as_bytes()));
+    let (stem, ext) = split_extension(filename);
+
+    (8..=short_id.len()).step_by(4).map(move |len| {
+        fit_filename(stem, &format!("_{}{}", &short_id[..len], ext), target_fs)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::StoredItemBuilder;
+
+    fn store(name: &str) -> StoredItemStore {
+        let path = std::env::temp_dir().join(format!("rs-google-photos-sync-{}.data", name));
+        let _ = fs::remove_file(&path);
+
+        StoredItemStore::new(path.to_str().unwrap())
+    }
+
+    fn add(storage: &mut StoredItemStore, id: &str, filename: &str, created_day: u32, downloaded: bool) {
+        let stored_item = StoredItemBuilder::new(id, filename).created_on(created_day);
+
+        if downloaded { stored_item.downloaded(None, None) } else { stored_item }.add_to(storage);
+    }
+
+    fn filename(storage: &StoredItemStore, id: &str) -> FileName {
+        storage.get(&id.to_owned()).unwrap().get_filename()
+    }
+
+    #[test]
+    pub fn oldest_duplicate_keeps_original_name() {
+        let mut storage = store("oldest-keeps-name");
+        add(&mut storage, "b", "IMG.jpg", 2, false);
+        add(&mut storage, "a", "IMG.jpg", 1, false);
+        add(&mut storage, "c", "IMG.jpg", 3, false);
+
+        assert_eq!(resolve_filenames(&mut storage, TargetFs::Posix, false), 2);
+
+        assert_eq!(filename(&storage, "a"), "IMG.jpg");
+        assert!(filename(&storage, "b").starts_with("IMG_"));
+        assert!(filename(&storage, "b").ends_with(".jpg"));
+        assert_ne!(filename(&storage, "b"), filename(&storage, "c"));
+    }
+
+    #[test]
+    pub fn resolution_is_stable() {
+        let mut first = store("stable-first");
+        add(&mut first, "a", "IMG.jpg", 1, false);
+        add(&mut first, "b", "IMG.jpg", 1, false);
+        resolve_filenames(&mut first, TargetFs::Posix, false);
+
+        let mut second = store("stable-second");
+        add(&mut second, "b", "IMG.jpg", 1, false);
+        add(&mut second, "a", "IMG.jpg", 1, false);
+        resolve_filenames(&mut second, TargetFs::Posix, false);
+
+        assert_eq!(filename(&first, "a"), filename(&second, "a"));
+        assert_eq!(filename(&first, "b"), filename(&second, "b"));
+        assert_eq!(resolve_filenames(&mut first, TargetFs::Posix, false), 0);
+    }
+
+    #[test]
+    pub fn a_page_is_resolved_without_the_rest_of_the_catalog() {
+        let mut storage = store("page-only");
+        add(&mut storage, "stored", "IMG.jpg", 1, true);
+        add(&mut storage, "other", "IMG:1.jpg", 2, false);
+        add(&mut storage, "b", "IMG.jpg", 4, false);
+        add(&mut storage, "a", "IMG.jpg", 3, false);
+
+        let mut taken = TakenNames::of(&storage, false);
+        let page = vec!["a".to_owned(), "b".to_owned()];
+        assert_eq!(resolve_filenames_of(&mut storage, &page, &mut taken, TargetFs::Windows), 2);
+
+        assert!(filename(&storage, "a").starts_with("IMG_"));
+        assert_ne!(filename(&storage, "a"), filename(&storage, "b"));
+        assert_eq!(filename(&storage, "other"), "IMG:1.jpg");
+        assert!(taken.contains(&filename(&storage, "b")));
+    }
+
+    #[test]
+    pub fn downloaded_file_is_never_renamed() {
+        let mut storage = store("downloaded-not-renamed");
+        add(&mut storage, "old", "IMG.jpg", 1, false);
+        add(&mut storage, "new", "IMG.jpg", 5, true);
+
+        resolve_filenames(&mut storage, TargetFs::Posix, false);
+
+        assert_eq!(filename(&storage, "new"), "IMG.jpg");
+        assert_ne!(filename(&storage, "old"), "IMG.jpg");
+    }
+
+    #[test]
+    pub fn suffix_does_not_collide_with_real_filename() {
+        let suffixed = suffixed_filenames("IMG.jpg", &"b".to_owned(), TargetFs::Posix).next().unwrap();
+
+        let mut storage = store("suffix-collision");
+        add(&mut storage, "a", "IMG.jpg", 1, false);
+        add(&mut storage, "b", "IMG.jpg", 2, false);
+        add(&mut storage, "c", &suffixed, 3, true);
+
+        resolve_filenames(&mut storage, TargetFs::Posix, false);
+
+        let names = vec![filename(&storage, "a"), filename(&storage, "b"), filename(&storage, "c")];
+        assert_eq!(names.iter().collect::<HashSet<_>>().len(), 3);
+        assert_eq!(filename(&storage, "c"), suffixed);
+    }
+
+    #[test]
+    pub fn case_folded_names_collide_only_when_folding() {
+        let mut storage = store("case-folding");
+        add(&mut storage, "a", "IMG.JPG", 1, false);
+        add(&mut storage, "b", "img.jpg", 2, false);
+
+        assert_eq!(resolve_filenames(&mut storage, TargetFs::Posix, false), 0);
+        assert_eq!(resolve_filenames(&mut storage, TargetFs::Posix, true), 1);
+        assert_eq!(filename(&storage, "a"), "IMG.JPG");
+        assert!(filename(&storage, "b").starts_with("img_"));
+    }
+
+    #[test]
+    pub fn legacy_prefix_is_replaced_when_not_downloaded() {
+        let mut storage = store("legacy-prefix");
+        add(&mut storage, "a", "IMG.jpg", 1, false);
+        add(&mut storage, "b", "0_IMG.jpg", 2, false);
+
+        let mut item = storage.get_cloned(&"a".to_owned()).unwrap();
+        item.alt_filename = Some("0_IMG.jpg".to_owned());
+        storage.set(&"a".to_owned(), item);
+
+        resolve_filenames(&mut storage, TargetFs::Posix, false);
+
+        assert_eq!(filename(&storage, "a"), "IMG.jpg");
+        assert_eq!(filename(&storage, "b"), "0_IMG.jpg");
+    }
+
+    #[test]
+    pub fn sanitizes_separators_and_reserved_names() {
+        assert_eq!(sanitize_filename("a/b:c.jpg", TargetFs::Posix), "a_b:c.jpg");
+        assert_eq!(sanitize_filename("a/b:c\\d.jpg", TargetFs::Windows), "a_b_c_d.jpg");
+        assert_eq!(sanitize_filename("con.jpg", TargetFs::Windows), "_con.jpg");
+        assert_eq!(sanitize_filename("CON.tar.gz", TargetFs::Windows), "_CON.tar.gz");
+        assert_eq!(sanitize_filename("console.jpg", TargetFs::Windows), "console.jpg");
+        assert_eq!(sanitize_filename("con.jpg", TargetFs::Exfat), "con.jpg");
+        assert_eq!(sanitize_filename("photo. ", TargetFs::Exfat), "photo__");
+        assert_eq!(sanitize_filename("..", TargetFs::Posix), "__");
+    }
+
+    #[test]
+    pub fn truncates_long_names_keeping_extension() {
+        let long = format!("{}.jpg", "ж".repeat(200));
+
+        let posix = sanitize_filename(&long, TargetFs::Posix);
+        assert!(posix.len() <= MAX_NAME_LEN);
+        assert!(posix.ends_with(".jpg"));
+
+        assert_eq!(sanitize_filename(&long, TargetFs::Exfat), long);
+
+        let long_extension = format!("a.{}", "x".repeat(300));
+        assert_eq!(sanitize_filename(&long_extension, TargetFs::Posix).len(), MAX_NAME_LEN);
+        let suffixed = suffixed_filenames("a.jpg", &"b".to_owned(), TargetFs::Posix).next().unwrap();
+        let suffix = &suffixed[1..suffixed.len() - 4];
+        let long_suffixed = suffixed_filenames(&long_extension, &"b".to_owned(), TargetFs::Posix).next().unwrap();
+        assert_eq!(long_suffixed.len(), MAX_NAME_LEN);
+        assert!(long_suffixed.starts_with(suffix));
+    }
+
+    #[test]
+    pub fn a_cut_name_does_not_end_in_a_dot_or_space() {
+        let no_extension = format!("{}  bbb", "a".repeat(254));
+        let expected = format!("{}_", "a".repeat(254));
+
+        assert_eq!(sanitize_filename(&no_extension, TargetFs::Windows), expected);
+        assert_eq!(sanitize_filename(&no_extension, TargetFs::Exfat), expected);
+        assert_eq!(sanitize_filename(&no_extension, TargetFs::Posix), format!("{} ", "a".repeat(254)));
+    }
+
+    #[test]
+    pub fn resolver_stores_sanitized_name() {
+        let mut storage = store("sanitized-name");
+        add(&mut storage, "a", "a:b.jpg", 1, false);
+        add(&mut storage, "b", "a_b.jpg", 2, false);
+
+        resolve_filenames(&mut storage, TargetFs::Windows, true);
+
+        assert_eq!(filename(&storage, "a"), "a_b.jpg");
+        assert_eq!(storage.get(&"a".to_owned()).unwrap().mediaItem.filename, "a:b.jpg");
+        assert!(filename(&storage, "b").starts_with("a_b_"));
+    }
+
+    #[test]
+    pub fn the_case_probe_leaves_the_library_as_it_was() {
+        let dir = std::env::temp_dir().join("rs-google-photos-sync-case-probe");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(".case_probe"), b"kept").unwrap();
+
+        detect_case_insensitive_fs(&dir);
+
+        let names = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect::<Vec<_>>();
+        assert_eq!(names, vec![".case_probe"]);
+        assert_eq!(fs::read(dir.join(".case_probe")).unwrap(), b"kept");
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::Datelike;
+use futures::StreamExt;
+use futures::stream;
+use image::ImageFormat;
+use image::io::Reader;
+use log::error;
+
+use crate::{StoredItem, StoredItemStore};
+use crate::destinations::Destination;
+use crate::error::{CustomError, CustomResult};
+use crate::util;
+
+const THUMBS_DIR: &str = "thumbs";
+
+const STYLE: &str = "body{font-family:sans-serif;margin:1em 2em} \
+    .grid{display:flex;flex-wrap:wrap;gap:8px} \
+    figure{margin:0;width:var(--size);font-size:12px;overflow:hidden} \
+    figure img,.placeholder{width:var(--size);height:var(--size);object-fit:cover;background:#eee;display:block} \
+    .placeholder{display:flex;align-items:center;justify-content:center;color:#666;text-decoration:none} \
+    figcaption{white-space:nowrap;overflow:hidden;text-overflow:ellipsis}";
+
+#[derive(Debug, Default)]
+pub struct GalleryReport {
+    pub items: usize,
+    pub pages: usize,
+    pub thumbnails: usize,
+    pub cached: usize,
+    pub failed: usize,
+}
+
+struct GalleryItem<'a> {
+    stored_item: &'a StoredItem,
+    original: String,
+    thumbnail: Option<String>,
+}
+
+// Renders the downloaded files of a local destination as static pages in gallery_dir: an index,
+// a page per year, month and album. album_titles maps album ids to the names shown, an album
+// without one is shown by its id. Thumbnails are cached in gallery_dir/thumbs/<size> by content,
+// so only new or changed files are decoded again.
+pub async fn generate_gallery(storage: &StoredItemStore, destination: &Destination, gallery_dir: &Path,
+                              album_titles: &HashMap<String, String>, thumbnail_size: u32, parallel: usize)
+                              -> CustomResult<GalleryReport>
+{
+    let mut report = GalleryReport::default();
+    let replica = destination.replica();
+
+    let library = destination.backend.local_root()
+        .ok_or_else(|| CustomError::Err(format!("{} is not on a local disk", destination.name)))?;
+    let thumbs_href = format!("{}/{}", THUMBS_DIR, thumbnail_size);
+    let thumbs_dir = gallery_dir.join(THUMBS_DIR).join(thumbnail_size.to_string());
+    fs::create_dir_all(&thumbs_dir)?;
+
+    let library_href = relative_href(&gallery_dir.canonicalize()?, &library.canonicalize()?);
+
+    let mut stored_items = storage.get_all()
+        .into_iter()
+        .filter(|stored_item| stored_item.get_download_info_at(replica).is_some())
+        .collect::<Vec<_>>();
+    stored_items.sort_by(|a, b| {
+        a.creation_time().cmp(&b.creation_time()).then_with(|| a.get_filename().cmp(&b.get_filename()))
+    });
+
+    let mut pending = Vec::new();
+    let mut items = Vec::with_capacity(stored_items.len());
+
+    for stored_item in stored_items {
+        let filename = stored_item.get_filename();
+        let mut thumbnail = None;
+
+        if stored_item.is_photo() {
+            let info = stored_item.get_download_info_at(replica).unwrap();
+            let name = format!("{}.jpg", info.sha256.as_ref().unwrap_or(&stored_item.mediaItem.id));
+
+            if thumbs_dir.join(&name).exists() {
+                report.cached += 1;
+            } else {
+                pending.push((items.len(), library.join(&filename), thumbs_dir.join(&name)));
+            }
+
+            thumbnail = Some(format!("{}/{}", thumbs_href, name));
+        }
+
+        items.push(GalleryItem {
+            stored_item,
+            original: format!("{}/{}", library_href, util::uri_encode(&filename, true)),
+            thumbnail,
+        });
+    }
+
+    let failed = stream::iter(pending)
+        .map(|(i, original, thumbnail)| async move {
+            let res = tokio::task::spawn_blocking({
+                let (original, thumbnail) = (original.clone(), thumbnail.clone());
+                move || write_thumbnail(&original, &thumbnail, thumbnail_size)
+            }).await;
+
+            match res.map_err(CustomError::from).and_then(|res| res) {
+                Ok(_) => None,
+                Err(e) => {
+                    error!("Error creating thumbnail of {} {}", original.display(), e);
+                    Some(i)
+                }
+            }
+        })
+        .buffer_unordered(parallel.max(1))
+        .filter_map(|failed| async move { failed })
+        .collect::<Vec<_>>()
+        .await;
+
+    for i in failed.iter() {
+        items[*i].thumbnail = None;
+    }
+
+    report.items = items.len();
+    report.failed = failed.len();
+    report.thumbnails = items.iter().filter(|item| item.thumbnail.is_some()).count() - report.cached;
+
+    let mut months: BTreeMap<(i32, u32), Vec<&GalleryItem>> = BTreeMap::new();
+    let mut albums: BTreeMap<&str, Vec<&GalleryItem>> = BTreeMap::new();
+
+    for item in items.iter() {
+        let created = item.stored_item.creation_time();
+        months.entry((created.year(), created.month())).or_default().push(item);
+
+        for album in item.stored_item.albums.iter().flatten() {
+            albums.entry(album).or_default().push(item);
+        }
+    }
+
+    let mut years: BTreeMap<i32, Vec<(u32, usize)>> = BTreeMap::new();
+    for ((year, month), month_items) in months.iter() {
+        years.entry(*year).or_default().push((*month, month_items.len()));
+    }
+
+    let mut index = format!("<h1>Library</h1>\n<p>{} files</p>\n<h2>Years</h2>\n<ul>\n", items.len());
+    for (year, year_months) in years.iter().rev() {
+        let count = year_months.iter().map(|(_, count)| count).sum::<usize>();
+        index.push_str(&format!("<li><a href=\"{}.html\">{}</a> ({})</li>\n", year, year, count));
+    }
+    index.push_str("</ul>\n");
+
+    if !albums.is_empty() {
+        index.push_str("<h2>Albums</h2>\n<ul>\n");
+        let mut by_title = albums.iter().collect::<Vec<_>>();
+        by_title.sort_by_key(|(album, _)| album_title(album_titles, album).to_lowercase());
+
+        for (album, album_items) in by_title {
+            index.push_str(&format!("<li><a href=\"{}\">{}</a> ({})</li>\n", album_page(album),
+                                    util::html_escape(album_title(album_titles, album)), album_items.len()));
+        }
+        index.push_str("</ul>\n");
+    }
+
+    write_page(gallery_dir, "index.html", "Library", &index, thumbnail_size)?;
+    report.pages += 1;
+
+    for (year, year_months) in years.iter() {
+        let mut body = format!("<p><a href=\"index.html\">Library</a></p>\n<h1>{}</h1>\n<ul>\n", year);
+        for (month, count) in year_months {
+            body.push_str(&format!("<li><a href=\"{}\">{}</a> ({})</li>\n",
+                                   month_page(*year, *month), month_name(*year, *month), count));
+        }
+        body.push_str("</ul>\n");
+
+        write_page(gallery_dir, &format!("{}.html", year), &year.to_string(), &body, thumbnail_size)?;
+        report.pages += 1;
+    }
+
+    for ((year, month), month_items) in months.iter() {
+        let title = month_name(*year, *month);
+        let body = format!("<p><a href=\"index.html\">Library</a> / <a href=\"{}.html\">{}</a></p>\n<h1>{}</h1>\n{}",
+                           year, year, title, grid(month_items, album_titles));
+
+        write_page(gallery_dir, &month_page(*year, *month), &title, &body, thumbnail_size)?;
+        report.pages += 1;
+    }
+
+    for (album, album_items) in albums.iter() {
+        let title = util::html_escape(album_title(album_titles, album));
+        let body = format!("<p><a href=\"index.html\">Library</a></p>\n<h1>{}</h1>\n{}",
+                           title, grid(album_items, album_titles));
+
+        write_page(gallery_dir, &album_page(album), &title, &body, thumbnail_size)?;
+        report.pages += 1;
+    }
+
+    Ok(report)
+}
+
+fn write_thumbnail(original: &Path, thumbnail: &Path, size: u32) -> CustomResult<()> {
+    let image = Reader::open(original)?
+        .with_guessed_format()?
+        .decode()
+        .map_err(|e| CustomError::Err(format!("cannot decode image {}", e)))?;
+
+    let tmp = thumbnail.with_extension("jpg.tmp");
+    image.thumbnail(size, size)
+        .to_rgb8()
+        .save_with_format(&tmp, ImageFormat::Jpeg)
+        .map_err(|e| CustomError::Err(format!("cannot write thumbnail {}", e)))?;
+    fs::rename(&tmp, thumbnail)?;
+
+    Ok(())
+}
+
+fn write_page(gallery_dir: &Path, name: &str, title: &str, body: &str, thumbnail_size: u32) -> CustomResult<()> {
+    let html = format!("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title>\n\
+        <style>:root{{--size:{}px}} {}</style></head><body>\n{}</body></html>\n",
+                       title, thumbnail_size, STYLE, body);
+
+    fs::write(gallery_dir.join(name), html)?;
+
+    Ok(())
+}
+
+fn grid(items: &[&GalleryItem], album_titles: &HashMap<String, String>) -> String {
+    let mut html = String::from("<div class=\"grid\">\n");
+
+    for item in items {
+        let stored_item = item.stored_item;
+        let meta = &stored_item.mediaItem.mediaMetadata;
+        let filename = util::html_escape(&stored_item.get_filename());
+
+        let mut details = vec![
+            stored_item.get_filename(),
+            stored_item.creation_time().format("%Y-%m-%d %H:%M:%S").to_string(),
+        ];
+        if let (Some(width), Some(height)) = (meta.width.as_ref(), meta.height.as_ref()) {
+            details.push(format!("{}x{}", width, height));
+        }
+        if let Some(size) = stored_item.get_download_info().and_then(|info| info.size) {
+            details.push(format!("{} bytes", size));
+        }
+        if let Some(albums) = stored_item.albums.as_ref() {
+            let titles = albums.iter().map(|album| album_title(album_titles, album)).collect::<Vec<_>>();
+            details.push(format!("albums: {}", titles.join(", ")));
+        }
+
+        let preview = match item.thumbnail.as_ref() {
+            Some(thumbnail) => format!("<img src=\"{}\" loading=\"lazy\" alt=\"{}\">", thumbnail, filename),
+            None => format!("<span class=\"placeholder\">{}</span>", if stored_item.is_photo() { "photo" } else { "video" }),
+        };
+
+        html.push_str(&format!("<figure><a href=\"{}\" title=\"{}\">{}</a>\
+            <figcaption>{} <a href=\"{}\">&#8599;</a></figcaption></figure>\n",
+                               util::html_escape(&item.original), util::html_escape(&details.join("\n")), preview,
+                               filename, util::html_escape(&stored_item.mediaItem.get_product_url())));
+    }
+
+    html.push_str("</div>\n");
+    html
+}
+
+fn month_page(year: i32, month: u32) -> String {
+    format!("{}-{:02}.html", year, month)
+}
+
+fn month_name(year: i32, month: u32) -> String {
+    chrono::NaiveDate::from_ymd_opt(year, month, 1)
+        .map(|date| date.format("%B %Y").to_string())
+        .unwrap_or_else(|| format!("{}-{:02}", year, month))
+}
+
+fn album_title<'a>(album_titles: &'a HashMap<String, String>, album: &'a str) -> &'a str {
+    album_titles.get(album).map_or(album, String::as_str)
+}
+
+fn album_page(album: &str) -> String {
+    let name = album.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect::<String>();
+
+    format!("album-{}.html", name)
+}
+
+// link from the gallery to the library, relative so both can be moved together
+fn relative_href(from: &Path, to: &Path) -> String {
+    let from = from.components().collect::<Vec<_>>();
+    let to = to.components().collect::<Vec<_>>();
+    let common = from.iter().zip(to.iter()).take_while(|(a, b)| a == b).count();
+
+    // different drives on windows
+    if common == 0 {
+        let path = to.iter().collect::<PathBuf>().to_string_lossy().replace('\\', "/");
+        return format!("file:///{}", util::uri_encode(path.trim_start_matches('/'), false).replacen("%3A", ":", 1));
+    }
+
+    let mut parts = vec!["..".to_owned(); from.len() - common];
+    parts.extend(to[common..].iter().map(|component| util::uri_encode(&component.as_os_str().to_string_lossy(), true)));
+
+    if parts.is_empty() { ".".to_owned() } else { parts.join("/") }
+}
+
+#[cfg(test)]
+mod test {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+    use crate::storage_backend::LocalBackend;
+    use crate::test::StoredItemBuilder;
+
+    fn add(storage: &mut StoredItemStore, id: &str, filename: &str, photo: bool, downloaded: bool) {
+        let mut stored_item = StoredItemBuilder::new(id, filename).dimensions(64, 48).albums(&["holiday"]);
+        if !photo {
+            stored_item = stored_item.video();
+        }
+        if downloaded {
+            stored_item = stored_item.downloaded(None, None);
+        }
+
+        stored_item.add_to(storage);
+    }
+
+    #[test]
+    pub fn gallery_links_thumbnails_to_local_originals() {
+        let dir = std::env::temp_dir().join("rs-google-photos-sync-gallery");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("library")).unwrap();
+
+        let mut storage = StoredItemStore::new(dir.join("data").to_str().unwrap());
+        add(&mut storage, "1", "beach 1.png", true, true);
+        add(&mut storage, "2", "clip.mp4", false, true);
+        add(&mut storage, "3", "later.jpg", true, false);
+
+        RgbImage::from_pixel(64, 48, Rgb([200, 100, 50])).save(dir.join("library/beach 1.png")).unwrap();
+        fs::write(dir.join("library/clip.mp4"), b"not a picture").unwrap();
+
+        let destination = Destination {
+            name: "primary".to_owned(),
+            primary: true,
+            backend: Box::new(LocalBackend::new(dir.join("library").to_str().unwrap())),
+        };
+        let gallery_dir = dir.join("gallery");
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let album_titles = HashMap::from([("holiday".to_owned(), "Summer & sea".to_owned())]);
+
+        let report = runtime.block_on(generate_gallery(&storage, &destination, &gallery_dir, &album_titles, 16, 2)).unwrap();
+        assert_eq!((report.items, report.thumbnails, report.cached, report.failed), (2, 1, 0, 0));
+        assert_eq!(report.pages, 4);
+
+        let thumbnail = image::open(gallery_dir.join("thumbs/16/1.jpg")).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (16, 12));
+
+        let month = fs::read_to_string(gallery_dir.join("2019-01.html")).unwrap();
+        assert!(month.contains("<a href=\"../library/beach%201.png\""));
+        assert!(month.contains("<img src=\"thumbs/16/1.jpg\" loading=\"lazy\""));
+        assert!(month.contains("<span class=\"placeholder\">video</span>"));
+        assert!(!month.contains("later.jpg"));
+        assert!(month.contains("albums: Summer &amp; sea"));
+        assert!(fs::read_to_string(gallery_dir.join("index.html")).unwrap()
+            .contains("<a href=\"album-holiday.html\">Summer &amp; sea</a> (2)"));
+        assert!(fs::read_to_string(gallery_dir.join("album-holiday.html")).unwrap().contains("<h1>Summer &amp; sea</h1>"));
+
+        let report = runtime.block_on(generate_gallery(&storage, &destination, &gallery_dir, &album_titles, 16, 2)).unwrap();
+        assert_eq!((report.thumbnails, report.cached), (0, 1));
+
+        let report = runtime.block_on(generate_gallery(&storage, &destination, &gallery_dir, &HashMap::new(), 32, 2)).unwrap();
+        assert_eq!((report.thumbnails, report.cached), (1, 0));
+        assert!(fs::read_to_string(gallery_dir.join("album-holiday.html")).unwrap().contains("<h1>holiday</h1>"));
+    }
+}
@@ -15,13 +15,16 @@ use chrono::{DateTime, Utc};
 use nickel::{Nickel, HttpRouter, hyper::Url};
 
 use opener;
+use log::debug;
 
 use crate::util;
 use crate::error::{CustomResult};
+use crate::metrics::METRICS;
 
 const CALLBACK_URL: &'static str = "http://localhost:3001/oauth2redirect";
 
 pub struct GoogleAuthApi {
+    client: reqwest::Client,
     credentials: GoogleCredentials,
     pub token: Option<GoogleToken>,
 }
@@ -47,6 +50,10 @@ pub struct GoogleToken {
 }
 
 impl GoogleToken {
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        self.token_created_at + chrono::Duration::seconds(self.token.expires_in as i64)
+    }
+
     pub fn is_expired(&self) -> bool {
         let sec_from_token_creation = Utc::now().signed_duration_since(self.token_created_at).num_seconds();
 
@@ -64,19 +71,21 @@ pub struct GoogleApiToken {
 }
 
 impl GoogleAuthApi {
-    pub fn create() -> Self {
-        let auth = GoogleAuthApi::read_stored();
-
-        auth
+    pub fn create(client: reqwest::Client) -> Self {
+        GoogleAuthApi {
+            client,
+            credentials: GoogleCredentials::read_stored(),
+            token: Option::<GoogleToken>::read_stored(),
+        }
     }
 
-    pub fn authenticate_or_renew(&mut self) -> CustomResult<GoogleToken> {
+    pub async fn authenticate_or_renew(&mut self) -> CustomResult<GoogleToken> {
         if self.token.is_none() {
-            let token = self.authenticate()?;
+            let token = self.authenticate().await?;
             self.token = Some(token);
         } else {
             if self.token.as_ref().unwrap().is_expired() {
-                let token = self.renew_token()?;
+                let token = self.renew_token().await?;
                 self.token = Some(token);
             }
         }
@@ -84,35 +93,36 @@ impl GoogleAuthApi {
         Ok(self.token.clone().unwrap())
     }
 
-    fn authenticate(&self) -> CustomResult<GoogleToken> {
+    async fn authenticate(&self) -> CustomResult<GoogleToken> {
         let url = create_authorization_url(&self.credentials.web);
-        let code = get_authorization_code(url)?;
+        // waits for the browser on a thread of its own, the runtime keeps serving other tasks
+        let code = tokio::task::spawn_blocking(move || get_authorization_code(url)).await??;
 
-        println!("authorization code: {:#?}", code);
+        debug!("authorization code: {:#?}", code);
 
-        let api_token = get_token(&self.credentials.web, code)?;
-        println!("token {:#?}", api_token);
+        let api_token = get_token(&self.client, &self.credentials.web, code).await?;
+        debug!("token {:#?}", api_token);
 
         let token = GoogleToken {
             token: api_token,
             token_created_at: Utc::now()
         };
 
-        token.persist()?;
+        persist_token(&token).await?;
 
         Ok(token)
     }
 
-    fn renew_token(&self) -> CustomResult<GoogleToken> {
+    async fn renew_token(&self) -> CustomResult<GoogleToken> {
         let mut token = self.token.clone().unwrap();
 
-        let refresh_token = get_refresh_token(&self.credentials.web, &token.token)?;
+        let refresh_token = get_refresh_token(&self.client, &self.credentials.web, &token.token).await?;
 
         token.token.access_token = refresh_token.access_token;
         token.token.expires_in = refresh_token.expires_in;
         token.token_created_at = Utc::now();
 
-        token.persist()?;
+        persist_token(&token).await?;
 
         Ok(token)
     }
@@ -151,7 +161,7 @@ fn get_authorization_code(url: String) -> CustomResult<GoogleAuthorizationCode>
         let mut server = Nickel::new();
 
         server.get("/oauth2redirect", middleware! { |request|
-                println!("{}", &request.origin.remote_addr);
+                debug!("{}", &request.origin.remote_addr);
                 let query_params = parse_query_str(format!("{}", request.origin.uri));
                 tx.send(query_params).unwrap();
                 "Authenticated"
@@ -168,7 +178,7 @@ fn get_authorization_code(url: String) -> CustomResult<GoogleAuthorizationCode>
 
     let item: HashMap<String, String> = thread.join()?;
     let code = item.get("code").unwrap();
-    println!("thread result the code for access is: {}", code);
+    debug!("thread result the code for access is: {}", code);
 
     Ok(GoogleAuthorizationCode(String::from(code)))
 }
@@ -176,23 +186,25 @@ fn get_authorization_code(url: String) -> CustomResult<GoogleAuthorizationCode>
 fn parse_query_str(qstr: String) -> HashMap<String, String> {
     let mut query_params = HashMap::new();
 
-    println!("{}", qstr);
+    debug!("{}", qstr);
     let parsed = Url::parse(format!("http://localhost{}", &qstr).as_str()).unwrap();
 
     for (k, v) in parsed.query_pairs() {
-        println!("{} {}", k, v);
+        debug!("{} {}", k, v);
         query_params.insert(String::from(k), String::from(v));
     }
 
     query_params
 }
 
-fn get_token(credentials: &GoogleWebCredentials, code: GoogleAuthorizationCode) -> CustomResult<GoogleApiToken> {
+async fn get_token(client: &reqwest::Client, credentials: &GoogleWebCredentials, code: GoogleAuthorizationCode)
+                   -> CustomResult<GoogleApiToken>
+{
     let token_request: HashMap<String, String> = build_auth_token_request(
         &credentials, &code
     );
 
-    reqwest_token::<GoogleApiToken>(&credentials.token_uri, token_request)
+    reqwest_token::<GoogleApiToken>(client, &credentials.token_uri, token_request).await
 }
 
 #[derive(Deserialize, Debug)]
@@ -202,14 +214,16 @@ struct RefreshToken {
     pub token_type: String
 }
 
-fn get_refresh_token(credentials: &GoogleWebCredentials, api_token: &GoogleApiToken) -> CustomResult<RefreshToken> {
+async fn get_refresh_token(client: &reqwest::Client, credentials: &GoogleWebCredentials, api_token: &GoogleApiToken)
+                           -> CustomResult<RefreshToken>
+{
     let token_request: HashMap<String, String> = build_refresh_token_request(
         &credentials, &api_token
     );
 
-    println!("requiesting refresh token");
-    let resp = reqwest_token::<RefreshToken>(&credentials.token_uri, token_request).unwrap();
-    println!("resp {:#?}", resp);
+    debug!("requiesting refresh token");
+    let resp = reqwest_token::<RefreshToken>(client, &credentials.token_uri, token_request).await.unwrap();
+    debug!("resp {:#?}", resp);
     Ok(resp)
 }
 
@@ -239,34 +253,29 @@ fn build_refresh_token_request(credentials: &GoogleWebCredentials,
     refresh_request
 }
 
-fn reqwest_token<T>(token_uri: &String, token_request: HashMap<String, String>) -> CustomResult<T>
+async fn reqwest_token<T>(client: &reqwest::Client, token_uri: &str, token_request: HashMap<String, String>)
+                          -> CustomResult<T>
     where T: DeserializeOwned
 {
-    let client = reqwest::Client::new();
-
     let resp = client
-        .post(token_uri.as_str())
+        .post(token_uri)
         .json(&token_request)
-        .send()?
-        .json()?;
+        .send().await;
+    METRICS.api_call("token", resp.as_ref().ok().map(|resp| resp.status()));
+
+    let resp = resp?.json().await?;
 
     Ok(resp)
 }
 
-trait StorageLoader {
-    fn read_stored() -> Self;
-}
+async fn persist_token(token: &GoogleToken) -> CustomResult<()> {
+    let token = token.clone();
 
-impl StorageLoader for GoogleAuthApi {
-    fn read_stored() -> Self {
-        let credentials = GoogleCredentials::read_stored();
-        let token = Option::<GoogleToken>::read_stored();
+    tokio::task::spawn_blocking(move || token.persist()).await?
+}
 
-        GoogleAuthApi {
-            credentials,
-            token
-        }
-    }
+trait StorageLoader {
+    fn read_stored() -> Self;
 }
 
 impl StorageLoader for GoogleCredentials {
@@ -2,84 +2,173 @@ use std::convert::{From, Into};
 use std::option::Option;
 
 use chrono::{Datelike, DateTime, Duration, TimeZone, Utc};
-use reqwest::{ClientBuilder, header::HeaderMap, header::HeaderValue, Client};
-use reqwest::header;
+use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use log::{debug, error, info};
+use tokio::sync::watch;
 
 use crate::{MediaItem, util};
 use crate::downloader::DownloadUrl;
 use crate::error::{CustomError, CustomResult};
 use crate::google_api::GoogleToken;
+use crate::metrics::METRICS;
 
 pub struct GooglePhotosApi {
-    pub token: GoogleToken
+    pub client: Client,
+    pub token: GoogleToken,
+    // a renewed access token reaches the search pages still being fetched
+    access_token: watch::Sender<String>,
 }
 
 impl GooglePhotosApi {
-    pub fn search(&self, num_days_back: i32, limit_hint: usize) -> CustomResult<Vec<MediaItem>> {
-        search(&self.token, num_days_back, limit_hint)
+    pub fn new(client: Client, token: GoogleToken) -> GooglePhotosApi {
+        let (access_token, _) = watch::channel(token.token.access_token.to_owned());
+
+        GooglePhotosApi { client, token, access_token }
     }
 
-    pub fn batch_get(&self, media_item_ids: &Vec<String>) -> CustomResult<Vec<MediaItem>> {
-        batch_get(media_item_ids, &self.token)
+    pub fn set_token(&mut self, token: GoogleToken) {
+        self.access_token.send_replace(token.token.access_token.to_owned());
+        self.token = token;
     }
-}
 
-fn search(token: &GoogleToken, num_days_back: i32, limit_hint: usize)
-              -> CustomResult<Vec<MediaItem>>
-{
-    let mut headers = HeaderMap::new();
-    let token = format!("Bearer {}", token.token.access_token);
-    headers.insert(
-        header::AUTHORIZATION,
-        HeaderValue::from_str(&token)?,
-    );
+    // None searches the whole library
+    pub fn search_pages(&self, num_days_back: Option<i32>, limit_hint: usize) -> SearchPages {
+        let scope = match num_days_back {
+            Some(num_days_back) => SearchScope::DaysBack(num_days_back),
+            None => SearchScope::All,
+        };
+
+        SearchPages::new(self, scope, limit_hint)
+    }
 
-    let client = ClientBuilder::new().default_headers(headers).build()?;
+    pub async fn search_album(&self, album_id: &str, limit_hint: usize) -> CustomResult<Vec<MediaItem>> {
+        SearchPages::new(self, SearchScope::Album(album_id.to_owned()), limit_hint).collect_all().await
+    }
 
-    let mut media_items = Vec::<MediaItem>::new();
-    let mut page_token: Option<String> = None;
+    pub async fn batch_get(&self, media_item_ids: &Vec<String>) -> CustomResult<Vec<MediaItem>> {
+        batch_get(&self.client, media_item_ids, &self.token).await
+    }
 
-    while media_items.len() < limit_hint {
-        let resp = make_search_reqwest(&client, &page_token, num_days_back)?;
-        let mut resp_media_items = resp.mediaItems.or(Some(Vec::new())).unwrap();
-        println!("search result {} items {}/{}", resp_media_items.len(), media_items.len(), limit_hint);
+    // untitled albums have no title
+    pub async fn get_album_title(&self, album_id: &str) -> CustomResult<Option<String>> {
+        let url = format!("https://photoslibrary.googleapis.com/v1/albums/{}", album_id);
 
-        media_items.append(&mut resp_media_items);
+        let resp = self.client
+            .get(url.as_str())
+            .bearer_auth(&self.token.token.access_token)
+            .send().await;
+        METRICS.api_call("get_album", resp.as_ref().ok().map(|resp| resp.status()));
 
-        if let Some(next_page_token) = resp.nextPageToken {
-            page_token = Some(next_page_token);
-        } else {
-            break;
+        let album: Album = resp?.error_for_status()?.json().await?;
+
+        Ok(album.title)
+    }
+}
+
+enum SearchScope {
+    DaysBack(i32),
+    All,
+    Album(String),
+}
+
+// Fetches search results one page at a time so callers can process them without holding the whole result.
+// Owns a handle to the shared client, so it can be moved to its own task.
+pub struct SearchPages {
+    client: Client,
+    access_token: watch::Receiver<String>,
+    scope: SearchScope,
+    page_token: Option<String>,
+    fetched: usize,
+    limit_hint: usize,
+    done: bool,
+}
+
+impl SearchPages {
+    fn new(api: &GooglePhotosApi, scope: SearchScope, limit_hint: usize) -> SearchPages {
+        SearchPages {
+            client: api.client.clone(),
+            access_token: api.access_token.subscribe(),
+            scope,
+            page_token: None,
+            fetched: 0,
+            limit_hint,
+            done: false,
         }
     }
 
-    Ok(media_items)
+    pub async fn next_page(&mut self) -> CustomResult<Option<Vec<MediaItem>>> {
+        if self.done || self.fetched >= self.limit_hint {
+            return Ok(None);
+        }
+
+        let access_token = self.access_token.borrow().to_owned();
+        let resp = make_search_reqwest(&self.client, &access_token, &self.page_token, &self.scope).await?;
+        let resp_media_items = resp.mediaItems.unwrap_or_default();
+        info!("search result {} items {}/{}", resp_media_items.len(), self.fetched, self.limit_hint);
+
+        self.fetched += resp_media_items.len();
+        self.page_token = resp.nextPageToken;
+        self.done = self.page_token.is_none();
+
+        Ok(Some(resp_media_items))
+    }
+
+    // stopped at limit_hint with pages left
+    pub fn truncated(&self) -> bool {
+        !self.done && self.fetched >= self.limit_hint
+    }
+
+    async fn collect_all(mut self) -> CustomResult<Vec<MediaItem>> {
+        let mut media_items = Vec::<MediaItem>::new();
+
+        while let Some(mut page) = self.next_page().await? {
+            media_items.append(&mut page);
+        }
+
+        Ok(media_items)
+    }
 }
 
-fn make_search_reqwest(client: &Client, page_token: &Option<String>, days_back: i32) -> CustomResult<SearchResponse> {
-    let range = DateRange::range_from_days(days_back);
-    let search_filter = SearchFilter {
-        dateFilter: range.into(),
-        includeArchivedMedia: true,
+async fn make_search_reqwest(client: &Client, access_token: &str, page_token: &Option<String>, scope: &SearchScope)
+                             -> CustomResult<SearchResponse>
+{
+    // google does not allow filters together with an album id
+    let (album_id, filters) = match scope {
+        SearchScope::DaysBack(days_back) => {
+            let range = DateRange::range_from_days(*days_back);
+            let search_filter = SearchFilter {
+                dateFilter: range.into(),
+                includeArchivedMedia: true,
+            };
+
+            (None, Some(search_filter))
+        }
+        SearchScope::All => (None, None),
+        SearchScope::Album(album_id) => (Some(album_id.to_owned()), None),
     };
 
     let search_request = SearchRequest {
         pageSize: 100,
         pageToken: if page_token.is_some() { Some(page_token.as_ref().unwrap().to_owned()) } else { None },
-        filters: search_filter,
+        albumId: album_id,
+        filters,
     };
 
-    let mut resp = client
+    let resp = client
         .post("https://photoslibrary.googleapis.com/v1/mediaItems:search")
-        .json(&search_request).send()?;
+        .bearer_auth(access_token)
+        .json(&search_request).send().await;
+    METRICS.api_call("search", resp.as_ref().ok().map(|resp| resp.status()));
+
+    let resp = resp?;
 
-    let out = resp.json();
+    let text = resp.text().await?;
 
-    match out {
+    match serde_json::from_str(&text) {
         Ok(value) => Ok(value),
         Err(err) => {
-            println!("Error parsing output {} {}", err, resp.text().unwrap());
+            error!("Error parsing output {} {}", err, text);
             Err(CustomError::Err("parsing err".to_owned()))
         }
     }
@@ -97,7 +186,10 @@ struct SearchResponse {
 struct SearchRequest {
     pageSize: i32,
     pageToken: Option<String>,
-    filters: SearchFilter,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    albumId: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filters: Option<SearchFilter>,
 }
 
 #[derive(Serialize, Debug)]
@@ -154,18 +246,20 @@ impl<T: TimeZone> From<DateTime<T>> for Date {
     }
 }
 
-fn batch_get(media_item_ids: &Vec<String>, google_token: &GoogleToken) -> CustomResult<Vec<MediaItem>> {
+async fn batch_get(client: &Client, media_item_ids: &Vec<String>, google_token: &GoogleToken)
+                   -> CustomResult<Vec<MediaItem>>
+{
     const MAX_GOOGLE_BATCH_GET_SIZE: usize = 50;
 
     let groups = util::split_into_groups(media_item_ids, MAX_GOOGLE_BATCH_GET_SIZE);
-    println!("split {} items into {} groups", media_item_ids.len(), groups.len());
+    debug!("split {} items into {} groups", media_item_ids.len(), groups.len());
 
     let mut got = Vec::new();
 
     for group in groups {
-        let items = _batch_get(&group, google_token)?;
+        let items = _batch_get(client, &group, google_token).await?;
 
-        println!("fetched {}", items.len());
+        debug!("fetched {}", items.len());
 
         for item in items {
             got.push(item);
@@ -175,23 +269,22 @@ fn batch_get(media_item_ids: &Vec<String>, google_token: &GoogleToken) -> Custom
     Ok(got)
 }
 
-fn _batch_get(media_item_ids: &Vec<&String>, google_token: &GoogleToken) -> CustomResult<Vec<MediaItem>> {
+async fn _batch_get(client: &Client, media_item_ids: &Vec<&String>, google_token: &GoogleToken)
+                    -> CustomResult<Vec<MediaItem>>
+{
     let mut url = String::from("https://photoslibrary.googleapis.com/v1/mediaItems:batchGet?");
 
     for media_item_id in media_item_ids {
         url = url + &format!("mediaItemIds={}&", media_item_id);
     }
 
-    let mut headers = HeaderMap::new();
-    let token = format!("Bearer {}", google_token.token.access_token);
-    headers.insert(
-        header::AUTHORIZATION,
-        HeaderValue::from_str(&token)?,
-    );
+    let resp = client
+        .get(url.as_str())
+        .bearer_auth(&google_token.token.access_token)
+        .send().await;
+    METRICS.api_call("batch_get", resp.as_ref().ok().map(|resp| resp.status()));
 
-    let client = ClientBuilder::new().default_headers(headers).build()?;
-
-    let res: BatchGetResult = client.get(url.as_str()).send()?.json()?;
+    let res: BatchGetResult = resp?.json().await?;
 
     Ok(res.mediaItemResults
         .into_iter()
@@ -211,6 +304,11 @@ struct MediaItemResult {
     pub mediaItem: MediaItem,
 }
 
+#[derive(Deserialize, Debug)]
+struct Album {
+    pub title: Option<String>,
+}
+
 impl DownloadUrl for MediaItem {
     fn create_download_url(&self) -> CustomResult<String> {
         let mut url = String::new();
@@ -0,0 +1,216 @@
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+
+use crate::status::{SharedStatus, Status};
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct HealthConfig {
+    // ping the systemd watchdog (WatchdogSec=) while live
+    pub sd_notify: Option<bool>,
+    pub scheduler_stall_seconds: Option<i64>,
+    pub max_task_minutes: Option<i64>,
+    pub max_success_age_minutes: Option<i64>,
+}
+
+impl HealthConfig {
+    fn get_scheduler_stall_seconds(&self) -> i64 {
+        self.scheduler_stall_seconds.unwrap_or(60)
+    }
+
+    fn get_max_task_minutes(&self) -> i64 {
+        self.max_task_minutes.unwrap_or(360)
+    }
+
+    fn get_max_success_age_minutes(&self) -> i64 {
+        self.max_success_age_minutes.unwrap_or(180)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HealthReport {
+    pub ok: bool,
+    pub checks: Vec<Check>,
+}
+
+impl HealthReport {
+    fn new(checks: Vec<Check>) -> HealthReport {
+        HealthReport { ok: checks.iter().all(|check| check.ok), checks }
+    }
+}
+
+fn check(name: &'static str, ok: bool, detail: String) -> Check {
+    Check { name, ok, detail }
+}
+
+// Live while the scheduler thread ticks and no task hangs, a restart is the fix otherwise
+pub fn liveness(status: &Status, config: &HealthConfig, now: DateTime<Utc>) -> HealthReport {
+    HealthReport::new(liveness_checks(status, config, now))
+}
+
+fn liveness_checks(status: &Status, config: &HealthConfig, now: DateTime<Utc>) -> Vec<Check> {
+    let scheduler = match status.scheduler_tick {
+        Some(tick) => {
+            let age = now.signed_duration_since(tick).num_seconds();
+            check("scheduler", age <= config.get_scheduler_stall_seconds(), format!("last tick {}s ago", age))
+        }
+        None => check("scheduler", false, "not started".to_owned()),
+    };
+
+    let task = match (status.running.as_ref(), status.running_since) {
+        (Some(task), Some(since)) => {
+            let minutes = now.signed_duration_since(since).num_minutes();
+            check("task", minutes <= config.get_max_task_minutes(), format!("{} running for {}m", task, minutes))
+        }
+        _ => check("task", true, "idle".to_owned()),
+    };
+
+    vec![scheduler, task]
+}
+
+// Ready when also the catalog is loaded, the token is valid and every scheduled task succeeded recently.
+// Tasks that have not succeeded yet are given max_success_age_minutes from startup.
+pub fn readiness(status: &Status, config: &HealthConfig, tasks: &[&str], now: DateTime<Utc>) -> HealthReport {
+    let mut checks = liveness_checks(status, config, now);
+
+    checks.push(match status.catalog_loaded_at {
+        Some(_) => check("catalog", true, format!("{} items", status.totals.searched)),
+        None => check("catalog", false, "loading".to_owned()),
+    });
+
+    checks.push(match status.token_expires_at {
+        Some(expires_at) if expires_at > now => check("token", true, format!("expires {}", expires_at.to_rfc3339())),
+        Some(expires_at) => check("token", false, format!("expired {}", expires_at.to_rfc3339())),
+        None => check("token", false, "no token".to_owned()),
+    });
+
+    let max_age = config.get_max_success_age_minutes();
+    for task in tasks {
+        let since = status.last_success.get(*task).or(status.catalog_loaded_at.as_ref());
+        checks.push(match since {
+            Some(since) => {
+                let minutes = now.signed_duration_since(*since).num_minutes();
+                let detail = match status.last_success.contains_key(*task) {
+                    true => format!("{} succeeded {}m ago", task, minutes),
+                    false => format!("{} has not succeeded in {}m", task, minutes),
+                };
+                check("last_success", minutes <= max_age, detail)
+            }
+            None => check("last_success", false, format!("{} has not run", task)),
+        });
+    }
+
+    HealthReport::new(checks)
+}
+
+// Tells systemd the service is up, then pings the watchdog at half of WatchdogSec while live.
+// Does nothing unless sd_notify is on and the service runs with NOTIFY_SOCKET set.
+pub fn start_watchdog(status: SharedStatus, config: HealthConfig) {
+    let socket = match std::env::var("NOTIFY_SOCKET") {
+        Ok(socket) if config.sd_notify.unwrap_or(false) => socket,
+        _ => return,
+    };
+
+    if let Err(e) = sd_notify(&socket, "READY=1") {
+        error!("Error notifying systemd {}", e);
+    }
+
+    let interval = match std::env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse::<u64>().ok()) {
+        Some(usec) => Duration::from_micros(usec / 2),
+        None => return,
+    };
+
+    thread::spawn(move || loop {
+        let report = liveness(&status.lock().unwrap(), &config, Utc::now());
+
+        if report.ok {
+            if let Err(e) = sd_notify(&socket, "WATCHDOG=1") {
+                error!("Error notifying systemd {}", e);
+            }
+        } else {
+            warn!("Not pinging the watchdog {:?}", report.checks);
+        }
+
+        thread::sleep(interval);
+    });
+}
+
+#[cfg(target_os = "linux")]
+fn sd_notify(socket: &str, state: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let datagram = UnixDatagram::unbound()?;
+
+    // a leading @ is a socket in the abstract namespace
+    match socket.strip_prefix('@') {
+        Some(name) => datagram.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?,
+        None => datagram.send_to(state.as_bytes(), socket)?,
+    };
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sd_notify(_socket: &str, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+
+    #[test]
+    pub fn readiness_fails_on_stalled_scheduler_expired_token_and_old_success() {
+        let now = Utc.with_ymd_and_hms(2020, 5, 1, 12, 0, 0).unwrap();
+        let config = HealthConfig::default();
+
+        let mut status = Status::default();
+        assert!(!readiness(&status, &config, &[], now).ok);
+
+        status.scheduler_tick = Some(now - Duration::seconds(5));
+        status.catalog_loaded_at = Some(now - Duration::hours(1));
+        status.token_expires_at = Some(now + Duration::minutes(30));
+        status.last_success.insert("SearchFilesTask".to_owned(), now - Duration::minutes(20));
+        assert!(liveness(&status, &config, now).ok);
+        assert!(readiness(&status, &config, &["SearchFilesTask", "DownloadFilesTask"], now).ok);
+
+        // never succeeded since startup four hours ago
+        status.catalog_loaded_at = Some(now - Duration::hours(4));
+        let report = readiness(&status, &config, &["SearchFilesTask", "DownloadFilesTask"], now);
+        let failed = report.checks.iter().filter(|check| !check.ok).map(|check| &check.detail).collect::<Vec<_>>();
+        assert_eq!(failed, vec!["DownloadFilesTask has not succeeded in 240m"]);
+
+        status.token_expires_at = Some(now - Duration::minutes(1));
+        status.scheduler_tick = Some(now - Duration::minutes(5));
+        assert!(!liveness(&status, &config, now).ok);
+        assert_eq!(readiness(&status, &config, &["SearchFilesTask"], now).checks.iter().filter(|check| !check.ok).count(), 2);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    pub fn sd_notify_sends_the_state_as_a_datagram() {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = std::env::temp_dir().join("rs-google-photos-sync-notify.sock");
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        sd_notify(path.to_str().unwrap(), "WATCHDOG=1").unwrap();
+
+        let mut buf = [0u8; 32];
+        let read = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..read], b"WATCHDOG=1");
+    }
+}
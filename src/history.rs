@@ -0,0 +1,141 @@
+use chrono::{DateTime, Utc};
+
+use crate::my_db::KeyValueStore;
+use crate::notify::RunReport;
+
+// Every finished task run by run id, kept next to the catalog in secrets/
+pub type HistoryStore = KeyValueStore<RunReport>;
+
+pub trait JobHistory {
+    // keeps the max_runs most recent runs of each task, so a frequent task does not push out a weekly one
+    fn record(&mut self, report: RunReport, max_runs: usize);
+
+    fn last_started(&self, task: &str) -> Option<DateTime<Utc>>;
+
+    // start of the last run of any of tasks that did not fail, a search stopped at its limit does not count
+    fn last_succeeded(&self, tasks: &[&str]) -> Option<DateTime<Utc>>;
+
+    // newest first, of task when given
+    fn recent(&self, task: Option<&str>, limit: usize) -> Vec<&RunReport>;
+}
+
+impl JobHistory for HistoryStore {
+    fn record(&mut self, report: RunReport, max_runs: usize) {
+        let task = report.task.to_owned();
+        self.data.insert(report.run_id.to_owned(), report);
+
+        let mut started = self.data.values()
+            .filter(|run| run.task == task)
+            .map(|run| (run.started_at, run.run_id.to_owned()))
+            .collect::<Vec<_>>();
+
+        if started.len() > max_runs {
+            started.sort();
+            // the search window and missed jobs need the last successful run, however many failed since
+            let last_succeeded = self.data.values()
+                .filter(|run| run.task == task && run.ok && !run.truncated)
+                .max_by_key(|run| run.started_at)
+                .map(|run| run.run_id.to_owned());
+
+            for (_, run_id) in started.iter().take(started.len() - max_runs.max(1)) {
+                if Some(run_id) != last_succeeded.as_ref() {
+                    self.data.remove(run_id);
+                }
+            }
+        }
+    }
+
+    fn last_started(&self, task: &str) -> Option<DateTime<Utc>> {
+        self.data.values().filter(|run| run.task == task).map(|run| run.started_at).max()
+    }
+
+    fn last_succeeded(&self, tasks: &[&str]) -> Option<DateTime<Utc>> {
+        self.data.values()
+            .filter(|run| run.ok && !run.truncated && tasks.contains(&run.task.as_str()))
+            .map(|run| run.started_at)
+            .max()
+    }
+
+    fn recent(&self, task: Option<&str>, limit: usize) -> Vec<&RunReport> {
+        let mut runs = self.data
+            .values()
+            .filter(|run| task.is_none_or(|task| run.task == task))
+            .collect::<Vec<_>>();
+        runs.sort_by(|a, b| b.started_at.cmp(&a.started_at).then_with(|| b.run_id.cmp(&a.run_id)));
+        runs.truncate(limit);
+
+        runs
+    }
+}
+
+pub fn format_runs(runs: &[&RunReport]) -> String {
+    let mut text = format!("{:<20} {:<18} {:<14} {:>7} {:>6} {:>6} {:>6} {:>6} {:>12}  {}\n",
+                           "started", "task", "run id", "seconds", "found", "new", "down", "failed", "bytes", "outcome");
+
+    for run in runs {
+        let outcome = match &run.error {
+            Some(error) => format!("failed: {}", error),
+            None => "ok".to_owned(),
+        };
+
+        text.push_str(&format!("{:<20} {:<18} {:<14} {:>7} {:>6} {:>6} {:>6} {:>6} {:>12}  {}{}\n",
+                               run.started_at.format("%Y-%m-%d %H:%M:%S"), run.task, run.run_id, run.duration_seconds,
+                               run.found, run.new, run.downloaded, run.failed, run.bytes, outcome,
+                               if run.params.is_empty() { String::new() } else { format!(" ({})", run.params) }));
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+    use crate::error::CustomError;
+    use crate::status::RunCounts;
+
+    #[test]
+    pub fn record_keeps_the_most_recent_runs() {
+        let path = std::env::temp_dir().join("rs-google-photos-sync-history.data");
+        let _ = std::fs::remove_file(&path);
+        let mut history = HistoryStore::new(path.to_str().unwrap());
+        let start = Utc.with_ymd_and_hms(2020, 5, 1, 12, 0, 0).unwrap();
+
+        for (i, task) in ["SearchFilesTask", "DownloadFilesTask", "SearchFilesTask", "DownloadFilesTask", "DownloadFilesTask"].iter().enumerate() {
+            let mut report = RunReport::new(String::new(), task, "", &format!("run{}", i), start, &RunCounts::default(), 0, &Ok(()));
+            report.started_at = start + Duration::hours(i as i64);
+            history.record(report, 2);
+        }
+
+        // the oldest download is pruned, both searches are kept
+        assert_eq!(history.data.len(), 4);
+        assert!(history.get(&"run1".to_owned()).is_none());
+        assert_eq!(history.last_started("SearchFilesTask"), Some(start + Duration::hours(2)));
+        assert_eq!(history.recent(Some("DownloadFilesTask"), 10).iter().map(|run| run.run_id.as_str()).collect::<Vec<_>>(),
+                   vec!["run4", "run3"]);
+        assert_eq!(history.recent(None, 2).len(), 2);
+        assert!(format_runs(&history.recent(None, 1)).contains("2020-05-01 16:00:00  DownloadFilesTask"));
+    }
+
+    #[test]
+    pub fn the_last_complete_success_outlives_pruning() {
+        let path = std::env::temp_dir().join("rs-google-photos-sync-history-success.data");
+        let _ = std::fs::remove_file(&path);
+        let mut history = HistoryStore::new(path.to_str().unwrap());
+        let start = Utc.with_ymd_and_hms(2020, 5, 1, 12, 0, 0).unwrap();
+        let truncated = RunCounts { truncated: true, ..RunCounts::default() };
+
+        let runs = [(&RunCounts::default(), Ok(())), (&truncated, Ok(())),
+                    (&RunCounts::default(), Err(CustomError::Err("offline".to_owned()))), (&RunCounts::default(), Err(CustomError::Err("offline".to_owned())))];
+        for (i, (counts, res)) in runs.iter().enumerate() {
+            let mut report = RunReport::new(String::new(), "SearchFilesTask", "", &format!("run{}", i), start, counts, 0, res);
+            report.started_at = start + Duration::hours(i as i64);
+            history.record(report, 2);
+        }
+
+        assert_eq!(history.recent(None, 10).iter().map(|run| run.run_id.as_str()).collect::<Vec<_>>(),
+                   vec!["run3", "run2", "run0"]);
+        assert_eq!(history.last_succeeded(&["SearchFilesTask"]), Some(start));
+    }
+}
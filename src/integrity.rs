@@ -0,0 +1,210 @@
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+
+use crate::{MediaItemId, StoredItemStore};
+use crate::app_storage::AppStorage;
+use crate::error::CustomResult;
+use crate::storage_backend::StorageBackend;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileDigest {
+    pub size: u64,
+    pub sha256: Option<String>,
+}
+
+impl FileDigest {
+    pub fn size_only(size: u64) -> FileDigest {
+        FileDigest { size, sha256: None }
+    }
+}
+
+// fed with the downloaded chunks, so the downloader gets the digest without re-reading the file
+pub struct DigestBuilder {
+    hasher: Sha256,
+    size: u64,
+}
+
+impl DigestBuilder {
+    pub fn new() -> DigestBuilder {
+        DigestBuilder {
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.hasher.input(bytes);
+        self.size += bytes.len() as u64;
+    }
+
+    pub fn finish(self) -> FileDigest {
+        FileDigest {
+            size: self.size,
+            sha256: Some(format!("{:x}", self.hasher.result())),
+        }
+    }
+}
+
+pub async fn hash_object(backend: &dyn StorageBackend, name: &str) -> CustomResult<FileDigest> {
+    let mut digest = DigestBuilder::new();
+    let mut body = backend.get(name).await?;
+
+    while let Some(chunk) = body.next().await {
+        digest.update(&chunk?);
+    }
+
+    Ok(digest.finish())
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub hashed: usize,
+    pub missing: Vec<MediaItemId>,
+    pub truncated: Vec<MediaItemId>,
+    pub modified: Vec<MediaItemId>,
+}
+
+impl VerifyReport {
+    pub fn broken(&self) -> Vec<MediaItemId> {
+        let mut ids = Vec::new();
+        ids.extend(self.missing.iter().cloned());
+        ids.extend(self.truncated.iter().cloned());
+        ids.extend(self.modified.iter().cloned());
+
+        ids
+    }
+}
+
+enum FileState {
+    Ok,
+    Hashed(FileDigest),
+    Missing,
+    Truncated,
+    Modified,
+}
+
+pub async fn verify_library(storage: &mut StoredItemStore, backend: &dyn StorageBackend, replica: Option<&str>, unmark: bool)
+                            -> CustomResult<VerifyReport>
+{
+    let mut report = VerifyReport::default();
+    let mut hashed = Vec::new();
+
+    for stored_item in storage.get_all() {
+        let download_info = match stored_item.get_download_info_at(replica) {
+            Some(info) => info,
+            None => continue,
+        };
+
+        let id = stored_item.mediaItem.id.to_owned();
+        let name = stored_item.get_filename();
+
+        report.checked += 1;
+
+        match check_file(backend, &name, download_info.size, download_info.sha256.as_ref()).await? {
+            FileState::Ok => {}
+            FileState::Hashed(digest) => hashed.push((id, digest)),
+            FileState::Missing => report.missing.push(id),
+            FileState::Truncated => report.truncated.push(id),
+            FileState::Modified => report.modified.push(id),
+        }
+    }
+
+    report.hashed = hashed.len();
+    storage.set_digests(replica, &hashed);
+
+    if unmark {
+        storage.unmark_downloaded(replica, &report.broken());
+    }
+
+    storage.persist()?;
+
+    Ok(report)
+}
+
+async fn check_file(backend: &dyn StorageBackend, name: &str, size: Option<u64>, sha256: Option<&String>)
+                    -> CustomResult<FileState>
+{
+    let fs_size = match backend.stat(name).await? {
+        Some(info) => info.size,
+        None => return Ok(FileState::Missing),
+    };
+
+    if fs_size == 0 || size.is_some_and(|size| fs_size < size) {
+        return Ok(FileState::Truncated);
+    }
+
+    if size.is_some_and(|size| fs_size != size) {
+        return Ok(FileState::Modified);
+    }
+
+    let digest = hash_object(backend, name).await?;
+
+    match sha256 {
+        Some(sha256) if Some(sha256) == digest.sha256.as_ref() => Ok(FileState::Ok),
+        Some(_) => Ok(FileState::Modified),
+        None => Ok(FileState::Hashed(digest)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+    use crate::storage_backend::LocalBackend;
+    use crate::test::StoredItemBuilder;
+
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    pub fn the_digest_of_the_chunks_is_the_digest_of_the_file() {
+        let mut digest = DigestBuilder::new();
+        digest.update(b"ab");
+        digest.update(b"c");
+
+        assert_eq!(digest.finish(), FileDigest { size: 3, sha256: Some(ABC_SHA256.to_owned()) });
+    }
+
+    #[test]
+    pub fn verify_finds_missing_truncated_and_modified_files() {
+        let dir = std::env::temp_dir().join("rs-google-photos-sync-verify");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut storage = StoredItemStore::new(dir.join("data").to_str().unwrap());
+        StoredItemBuilder::new("ok", "ok.jpg").downloaded(Some(3), Some(ABC_SHA256)).add_to(&mut storage);
+        StoredItemBuilder::new("old", "old.jpg").downloaded(None, None).add_to(&mut storage);
+        StoredItemBuilder::new("truncated", "truncated.jpg").downloaded(Some(10), None).add_to(&mut storage);
+        StoredItemBuilder::new("modified", "modified.jpg").downloaded(Some(3), Some(ABC_SHA256)).add_to(&mut storage);
+        StoredItemBuilder::new("missing", "missing.jpg").downloaded(Some(3), Some(ABC_SHA256)).add_to(&mut storage);
+        StoredItemBuilder::new("pending", "pending.jpg").add_to(&mut storage);
+
+        fs::write(dir.join("ok.jpg"), b"abc").unwrap();
+        fs::write(dir.join("old.jpg"), b"abc").unwrap();
+        fs::write(dir.join("truncated.jpg"), b"abc").unwrap();
+        fs::write(dir.join("modified.jpg"), b"xyz").unwrap();
+
+        let backend = LocalBackend::new(dir.to_str().unwrap());
+        let verify = verify_library(&mut storage, &backend, None, true);
+        let report = tokio::runtime::Runtime::new().unwrap().block_on(verify).unwrap();
+
+        assert_eq!((report.checked, report.hashed), (5, 1));
+        assert_eq!(report.missing, vec!["missing".to_owned()]);
+        assert_eq!(report.truncated, vec!["truncated".to_owned()]);
+        assert_eq!(report.modified, vec!["modified".to_owned()]);
+
+        let old = storage.get(&"old".to_owned()).unwrap().get_download_info().unwrap();
+        assert_eq!((old.size, old.sha256.as_deref()), (Some(3), Some(ABC_SHA256)));
+        assert_eq!(old.downloaded_at, Utc.with_ymd_and_hms(2019, 2, 1, 12, 0, 0).unwrap());
+
+        for id in ["ok", "old"] {
+            assert!(storage.get(&id.to_owned()).unwrap().is_marked_downloaded());
+        }
+        for id in ["truncated", "modified", "missing"] {
+            assert!(!storage.get(&id.to_owned()).unwrap().is_marked_downloaded());
+        }
+    }
+}
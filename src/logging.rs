@@ -0,0 +1,154 @@
+use std::io::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use chrono::{DateTime, Local, Utc};
+use flexi_logger::{Cleanup, Criterion, DeferredNow, Duplicate, Logger, Naming, Record, ReconfigurationHandle};
+
+use crate::config::Config;
+use crate::error::{CustomError, CustomResult};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+// One slot for the whole process, the queue runs one task at a time. A task spreads over tokio
+// worker threads and spawn_blocking, a thread local would lose the run on the way.
+static CURRENT_RUN: Mutex<Option<(String, &'static str)>> = Mutex::new(None);
+static RUN_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+// Marks every line logged from now on with a new run id of task until end_run
+pub fn start_run(task: &'static str) -> String {
+    let counter = RUN_COUNTER.fetch_add(1, Ordering::Relaxed) & 0xffff;
+    let run_id = format!("{:08x}{:04x}", Utc::now().timestamp() as u32, counter);
+
+    *CURRENT_RUN.lock().unwrap() = Some((run_id.clone(), task));
+
+    run_id
+}
+
+pub fn end_run() {
+    *CURRENT_RUN.lock().unwrap() = None;
+}
+
+// Logs go to a file in log_dir (default next to the executable), rotated daily, and to stderr.
+// RUST_LOG overrides log_level.
+pub fn start_logger(config: &Config) -> CustomResult<ReconfigurationHandle> {
+    let log_dir = match config.log_dir.as_ref() {
+        Some(log_dir) => log_dir.to_owned(),
+        None => format!("{}", std::env::current_exe()?.parent().unwrap().display()),
+    };
+
+    let format = match config.log_format.unwrap_or_default() {
+        LogFormat::Text => text_format,
+        LogFormat::Json => json_format,
+    };
+
+    Logger::with_env_or_str(config.get_log_level())
+        .log_to_file()
+        .directory(log_dir)
+        .duplicate_to_stderr(Duplicate::All)
+        .format(format)
+        .rotate(Criterion::Age(flexi_logger::Age::Day), Naming::Timestamps, Cleanup::KeepLogFiles(3))
+        .start()
+        .map_err(|e| CustomError::Err(format!("cannot start logger {}", e)))
+}
+
+fn current_run() -> Option<(String, &'static str)> {
+    CURRENT_RUN.lock().unwrap().clone()
+}
+
+fn text_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> Result<(), std::io::Error> {
+    write_text(w, now.now(), record)
+}
+
+fn json_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> Result<(), std::io::Error> {
+    write_json(w, now.now(), record)
+}
+
+fn write_text(w: &mut dyn Write, now: &DateTime<Local>, record: &Record) -> Result<(), std::io::Error> {
+    let run = match current_run() {
+        Some((run_id, task)) => format!(" {} {}", task, run_id),
+        None => String::new(),
+    };
+
+    write!(w, "[{}] {} [{}]{} {}",
+           now.format("%Y-%m-%d %H:%M:%S%.6f %:z"), record.level(), record.target(), run, record.args())
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    ts: String,
+    level: String,
+    target: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    run_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    task: Option<&'static str>,
+    msg: String,
+}
+
+// one JSON object per line
+fn write_json(w: &mut dyn Write, now: &DateTime<Local>, record: &Record) -> Result<(), std::io::Error> {
+    let run = current_run();
+
+    let line = JsonLine {
+        ts: now.to_rfc3339(),
+        level: record.level().to_string(),
+        target: record.target(),
+        run_id: run.as_ref().map(|(run_id, _)| run_id.to_owned()),
+        task: run.map(|(_, task)| task),
+        msg: record.args().to_string(),
+    };
+
+    serde_json::to_writer(&mut *w, &line)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+    use log::Level;
+
+    use super::*;
+
+    #[test]
+    pub fn json_lines_carry_the_run_id_of_the_current_task() {
+        let now = Local.with_ymd_and_hms(2020, 5, 1, 12, 0, 0).unwrap();
+        let line = move |message: &str| {
+            let mut out = Vec::new();
+            write_json(&mut out, &now, &Record::builder()
+                .level(Level::Info)
+                .target("rs_google_photos_sync::downloader")
+                .args(format_args!("{}", message))
+                .build()).unwrap();
+            serde_json::from_slice::<serde_json::Value>(&out).unwrap()
+        };
+
+        let run_id = start_run("DownloadFilesTask");
+        let during = line("downloading \"a.jpg\"");
+        // the search page fetcher and the blocking file calls run on tokio threads of their own
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (spawned, blocking) = runtime.block_on(async {
+            (tokio::spawn(async move { line("search result 100 items") }).await.unwrap(),
+             tokio::task::spawn_blocking(move || line("hashing \"a.jpg\"")).await.unwrap())
+        });
+        end_run();
+        let after = line("idle");
+
+        assert_eq!(during["msg"], "downloading \"a.jpg\"");
+        assert_eq!(during["level"], "INFO");
+        assert_eq!(during["target"], "rs_google_photos_sync::downloader");
+        assert_eq!(during["run_id"], run_id.as_str());
+        assert_eq!(during["task"], "DownloadFilesTask");
+        assert_eq!(during["ts"], now.to_rfc3339().as_str());
+        assert_eq!(spawned["run_id"], run_id.as_str());
+        assert_eq!(blocking["run_id"], run_id.as_str());
+        assert!(after.get("run_id").is_none());
+    }
+}
@@ -7,7 +7,6 @@ extern crate log;
 extern crate nickel;
 extern crate opener;
 extern crate reqwest;
-extern crate scoped_threadpool;
 #[macro_use]
 extern crate serde;
 extern crate serde_json;
@@ -15,38 +14,72 @@ extern crate serde_json;
 extern crate winapi;
 
 use std::collections::{HashMap, HashSet};
-use std::iter::FromIterator;
 use std::option::Option;
 use std::path::Path;
 use std::process::Command;
+use std::time::Duration;
 use std::sync::{mpsc, Arc};
-use std::sync::mpsc::Receiver;
 use std::vec::Vec;
 
 use chrono::{DateTime, Utc};
 use commander::Commander;
 use log::{error, info, trace, warn};
 
-use app_storage::AppStorage;
-use scheduling::JobTask;
+use app_storage::{AppStorage, DownloadOrder, DownloadOrdering};
+use scheduling::{JobTask, SearchWindow};
 
+use crate::bandwidth::BandwidthLimiter;
+use crate::disk_space::SpaceGuard;
+use crate::downloader::Downloads;
 use crate::config::Config;
+use crate::config_sources::ConfigSources;
 use crate::error::{CustomError, CustomResult};
+use crate::filenames::{TakenNames, TargetFs};
 use crate::google_api::GoogleAuthApi;
 use crate::google_photos::GooglePhotosApi;
+use crate::integrity::FileDigest;
+use crate::storage_backend::StorageBackend;
+use crate::destinations::{Destination, DestinationName};
+use crate::dedup::{ContentIndex, DedupMode};
+use crate::perceptual::{PerceptualHash, ReportFormat};
+use crate::status::SharedStatus;
+use crate::metrics::METRICS;
+use crate::notify::{Notifications, RunReport};
+use crate::history::{HistoryStore, JobHistory};
+use crate::queue::TaskQueue;
+use crate::temp_files::SweepSummary;
 use std::sync::atomic::{AtomicBool};
-use flexi_logger::{Logger, LogTarget};
-use flexi_logger::writers::FileLogWriter;
 
 mod downloader;
 mod error;
 mod google_api;
+mod filenames;
 mod google_photos;
+mod integrity;
 mod my_db;
 mod util;
 mod config;
+mod config_check;
+mod config_sources;
 mod app_storage;
+mod bandwidth;
+mod disk_space;
 mod scheduling;
+mod temp_files;
+mod storage_backend;
+mod s3;
+mod destinations;
+mod dedup;
+mod perceptual;
+mod gallery;
+mod status;
+mod dashboard;
+mod metrics;
+mod health;
+mod logging;
+mod notify;
+mod history;
+mod queue;
 
 // =============
 // TODO: test periodic save db to file
@@ -59,6 +92,8 @@ pub struct StoredItem {
     pub mediaItem: MediaItem,
     pub appData: Option<AppData>,
     pub alt_filename: Option<String>,
+    pub albums: Option<Vec<String>>,
+    pub perceptual: Option<PerceptualHash>,
 }
 
 impl StoredItem {
@@ -70,6 +105,25 @@ impl StoredItem {
         }
     }
 
+    fn creation_time(&self) -> DateTime<Utc> {
+        self.mediaItem.mediaMetadata.creationTime
+    }
+
+    fn is_photo(&self) -> bool {
+        self.mediaItem.mediaMetadata.photo.is_some()
+    }
+
+    // the size is not known before downloading, pixel count is close enough to order by,
+    // videos are considered larger than any photo
+    fn estimated_size(&self) -> (bool, u64) {
+        let meta = &self.mediaItem.mediaMetadata;
+        let dimension = |value: &Option<String>| {
+            value.as_ref().and_then(|v| v.parse::<u64>().ok()).unwrap_or(0)
+        };
+
+        (!self.is_photo(), dimension(&meta.width) * dimension(&meta.height))
+    }
+
     fn is_marked_downloaded(&self) -> bool {
         if let Some(app_data) = &self.appData {
             app_data.download_info.is_some()
@@ -78,16 +132,87 @@ impl StoredItem {
         }
     }
 
-    fn mark_downloaded(&mut self) {
-        self.appData = Some(AppData {
-            download_info: Some(DownloadInfo {
-                downloaded_at: Utc::now()
-            })
-        });
+    fn is_downloaded_everywhere(&self, replicas: &[&str]) -> bool {
+        self.is_marked_downloaded() && replicas.iter().all(|replica| self.get_download_info_at(Some(replica)).is_some())
+    }
+
+    fn is_downloaded_anywhere(&self) -> bool {
+        self.is_marked_downloaded() || self.appData.as_ref().is_some_and(|app_data| app_data.replicas.is_some())
+    }
+
+    fn get_download_info(&self) -> Option<&DownloadInfo> {
+        self.get_download_info_at(None)
+    }
+
+    // replica None is the primary destination
+    fn get_download_info_at(&self, replica: Option<&str>) -> Option<&DownloadInfo> {
+        let app_data = self.appData.as_ref()?;
+
+        match replica {
+            None => app_data.download_info.as_ref(),
+            Some(replica) => app_data.replicas.as_ref().and_then(|replicas| replicas.get(replica)),
+        }
+    }
+
+    fn mark_downloaded_at(&mut self, replica: Option<&str>, digest: &FileDigest) {
+        let download_info = DownloadInfo {
+            downloaded_at: Utc::now(),
+            size: Some(digest.size),
+            sha256: digest.sha256.to_owned(),
+            linked_to: None,
+        };
+
+        let app_data = self.appData.get_or_insert(AppData { download_info: None, replicas: None });
+
+        match replica {
+            None => app_data.download_info = Some(download_info),
+            Some(replica) => {
+                app_data.replicas.get_or_insert_with(HashMap::new).insert(replica.to_owned(), download_info);
+            }
+        }
+    }
+
+    fn get_download_info_at_mut(&mut self, replica: Option<&str>) -> Option<&mut DownloadInfo> {
+        let app_data = self.appData.as_mut()?;
+
+        match replica {
+            None => app_data.download_info.as_mut(),
+            Some(replica) => app_data.replicas.as_mut().and_then(|replicas| replicas.get_mut(replica)),
+        }
+    }
+
+    fn mark_linked_at(&mut self, replica: Option<&str>, linked_to: &MediaItemId) {
+        if let Some(download_info) = self.get_download_info_at_mut(replica) {
+            download_info.linked_to = Some(linked_to.to_owned());
+        }
     }
 
-    fn unmark_downloaded(&mut self) {
-        self.appData = None;
+    // the file is already there, only its digest was not recorded yet
+    fn set_digest_at(&mut self, replica: Option<&str>, digest: &FileDigest) {
+        if let Some(download_info) = self.get_download_info_at_mut(replica) {
+            download_info.size = Some(digest.size);
+            download_info.sha256 = digest.sha256.to_owned();
+        }
+    }
+
+    fn unmark_downloaded_at(&mut self, replica: Option<&str>) {
+        if let Some(app_data) = self.appData.as_mut() {
+            match replica {
+                None => app_data.download_info = None,
+                Some(replica) => {
+                    if let Some(replicas) = app_data.replicas.as_mut() {
+                        replicas.remove(replica);
+                    }
+                    if app_data.replicas.as_ref().is_some_and(HashMap::is_empty) {
+                        app_data.replicas = None;
+                    }
+                }
+            }
+
+            if app_data.download_info.is_none() && app_data.replicas.is_none() {
+                self.appData = None;
+            }
+        }
     }
 }
 
@@ -99,6 +224,7 @@ pub type FileName = String;
 #[allow(non_snake_case)]
 pub struct MediaItem {
     pub id: MediaItemId,
+    pub productUrl: Option<String>,
     pub baseUrl: String,
     pub filename: String,
     pub mediaMetadata: MediaMetaData,
@@ -125,69 +251,126 @@ pub struct Video {}
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AppData {
     pub download_info: Option<DownloadInfo>,
+    pub replicas: Option<HashMap<DestinationName, DownloadInfo>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DownloadInfo {
     pub downloaded_at: DateTime<Utc>,
+    pub size: Option<u64>,
+    pub sha256: Option<String>,
+    // set when the file is a hardlink or reflink of the file of another media item with the same content
+    pub linked_to: Option<MediaItemId>,
 }
 
 pub type StoredItemStore = my_db::KeyValueStore<StoredItem>;
 
-fn main2() {
-    let exe_path = format!("{}", std::env::current_exe().unwrap().parent().unwrap().display());
-
-    let criterion = flexi_logger::Criterion::Age(flexi_logger::Age::Day);
+// commander registers an option by the part of its name after the last dash, so options use underscores
+fn cli() -> Commander {
+    Commander::new()
+        .usage_desc("Read-only sync Google Photos onto a local disk")
+        .after_desc("Commands:\n\n    config check  Validate the config, show the next scheduled runs and the defaults in use\n    config show   Show the merged config and where each value comes from\n")
+        .option_str("--config", "[path] Config file, JSON, TOML or YAML (default config.json or $XDG_CONFIG_HOME/rs-google-photos-sync/config.*)", None)
+        .option_list("--set", "[field=value ...] Override config fields, like http.address=0.0.0.0:3002", None)
+        .option_list("-s, --search", "[days back] [limit] Search and store media items", None)
+        .option_list("-d, --download", "[num files] Download media items", None)
+        .option_list("--sync", "[days back] [limit] Search and download new media items as pages arrive", None)
+        .option("--verify", "Rehash downloaded files and report missing, truncated or modified ones", None)
+        .option("--unmark", "With --verify, unmark broken files so they are downloaded again", None)
+        .option("--dedup", "Link downloaded files with identical content to a single copy", None)
+        .option_list("--duplicates", "[json|html] [output file] Report clusters of near-duplicate photos", None)
+        .option("--gallery", "Generate thumbnails and a static HTML gallery of the downloaded files", None)
+        .option("--history", "Show the last task runs from the job history", None)
+        .option_str("--task", "[task name] With --history, only runs of this task", None)
+        .option_int("--limit", "[num runs] With --history, how many runs to show (default 20)", None)
+        .option_str("--target_fs", "[posix|windows|exfat] Filesystem rules for file names (also --target-fs)", None)
+}
 
-    flexi_logger::Logger::with_env_or_str("info")
-        .log_target(LogTarget::StdOut)
-        .log_to_file()
-        .directory(exe_path)
-        .format(flexi_logger::detailed_format)
-        .print_message()
-        .rotate(criterion, flexi_logger::Naming::Timestamps, flexi_logger::Cleanup::KeepLogFiles(1))
-        .start()
-        .unwrap();
+// the dashed spellings of options are accepted too, commander only knows them with underscores
+fn parse_args(args: Vec<String>) -> Commander {
+    let args = args
+        .into_iter()
+        .map(|arg| match arg.as_str() {
+            "--target-fs" => "--target_fs".to_owned(),
+            _ => arg,
+        })
+        .collect();
+
+    cli().parse_list_or_exit(args)
+}
 
-    info!("Started application!");
+// commander leaves out a list option given without values, it is an empty list here
+fn get_list_or_flag(command: &Commander, name: &str) -> Option<Vec<String>> {
+    command.get_list(name).or_else(|| command.get_all_args().contains(&format!("--{}", name)).then(Vec::new))
 }
 
 fn main() -> CustomResult<()> {
-    let exe_path = format!("{}", std::env::current_exe().unwrap().parent().unwrap().display());
+    let command = parse_args(std::env::args().collect());
 
-    let criterion = flexi_logger::Criterion::Age(flexi_logger::Age::Day);
+    let sources = ConfigSources::from_args(command.get_str("config"), command.get_list("set").unwrap_or_default(), command.get_str("target_fs"))?;
 
-    flexi_logger::Logger::with_env_or_str("info")
-        .log_target(LogTarget::StdOut)
-        .log_to_file()
-        .directory(exe_path)
-        .format(flexi_logger::detailed_format)
-        .print_message()
-        .rotate(criterion, flexi_logger::Naming::Timestamps, flexi_logger::Cleanup::KeepLogFiles(3))
-        .start()
-        .unwrap();
+    // before loading the config, which fails on the first problem
+    match command.get_all_args().iter().take(2).map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["config", "check"] => return config_check::run_check(&sources),
+        ["config", "show"] => return config_check::run_show(&sources),
+        _ => {}
+    }
+
+    let config = config::init(sources)?;
+    let _logger = logging::start_logger(&config)?;
 
     info!("Started application!");
 
-    let command = Commander::new()
-        .usage_desc("Read-only sync Google Photos onto a local disk")
-        .option_list("-s, --search", "[days back] [limit] Search and store media items", None)
-        .option_list("-d, --download", "[num files] Download media items", None)
-        .parse_env_or_exit();
+    let history = HistoryStore::new("secrets/history.data");
+
+    // the job history is read from disk, no need to sign in
+    if command.get("history").unwrap_or(false) {
+        let limit = command.get_int("limit").unwrap_or(20).max(0) as usize;
+        let task = command.get_str("task");
+        print!("{}", history::format_runs(&history.recent(task.as_deref(), limit)));
+
+        return Ok(());
+    }
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let client = util::create_http_client()?;
 
     let storage = StoredItemStore::new("secrets/photos.data");
-    let mut google_auth = google_api::GoogleAuthApi::create();
-    let token = google_auth.authenticate_or_renew()?;
+    let mut google_auth = google_api::GoogleAuthApi::create(client.clone());
+    let token = runtime.block_on(google_auth.authenticate_or_renew())?;
+
+    let photos_api = GooglePhotosApi::new(client.clone(), token);
+
+    let destinations = destinations::create_destinations(&config, &client)?;
+    let case_insensitive_fs = destinations
+        .iter()
+        .filter_map(|destination| destination.backend.local_root())
+        .any(filenames::detect_case_insensitive_fs);
+    let target_fs = config.target_fs.unwrap_or_default();
+    for destination in &destinations {
+        info!("destination {} {}", destination.name, destination.backend.describe());
+    }
+    info!("target fs {:?}, case insensitive fs {}", target_fs, case_insensitive_fs);
 
-    let photos_api = GooglePhotosApi { token };
+    let bandwidth = BandwidthLimiter::new(&config.bandwidth_limit)?;
 
     let mut app = App {
+        config: config.clone(),
+        client,
+        bandwidth,
         google_auth,
         photos_api,
         storage,
+        destinations,
+        case_insensitive_fs,
+        target_fs,
+        status: SharedStatus::default(),
+        notifications: Notifications::default(),
+        history,
+        startup_sweep: None,
     };
 
-    mark_unmark_downloaded_photos_in_fs(&mut app)
+    runtime.block_on(mark_unmark_downloaded_photos_in_fs(&mut app))
         .map_err(|e| CustomError::Err(format!("could not mark/unmark downloaded {}", e)))?;
 
     let (tx, rx) = mpsc::channel();
@@ -196,23 +379,67 @@ fn main() -> CustomResult<()> {
         let days_back = search_params.get(0).unwrap().parse::<i32>()?;
         let default_limit = String::from("999999");
         let limit_hint = search_params.get(1).unwrap_or(&default_limit).parse::<usize>()?;
-        println!("search params days_back:{} limit:{}", days_back, limit_hint);
+        info!("search params days_back:{} limit:{}", days_back, limit_hint);
+
+        tx.send(JobTask::SearchFilesTask(SearchWindow::DaysBack(days_back), limit_hint)).unwrap();
+        drop(tx);
+    } else if let Some(sync_params) = command.get_list("sync") {
+        let days_back = sync_params.first().unwrap().parse::<i32>()?;
+        let default_limit = String::from("999999");
+        let limit_hint = sync_params.get(1).unwrap_or(&default_limit).parse::<usize>()?;
+        info!("sync params days_back:{} limit:{}", days_back, limit_hint);
 
-        tx.send(JobTask::SearchFilesTask(days_back, limit_hint)).unwrap();
+        tx.send(JobTask::SyncFilesTask(SearchWindow::DaysBack(days_back), limit_hint)).unwrap();
         drop(tx);
     } else if let Some(download_params) = command.get_list("download") {
         let num_items = download_params.get(0).unwrap().parse::<i32>()?;
-        println!("download params {}", num_items);
+        info!("download params {}", num_items);
 
         tx.send(JobTask::DownloadFilesTask(num_items)).unwrap();
         drop(tx);
+    } else if command.get("verify").unwrap_or(false) {
+        let unmark = command.get("unmark").unwrap_or(false);
+        info!("verify params unmark:{}", unmark);
+
+        tx.send(JobTask::VerifyFilesTask(unmark)).unwrap();
+        drop(tx);
+    } else if command.get("dedup").unwrap_or(false) {
+        tx.send(JobTask::DedupFilesTask).unwrap();
+        drop(tx);
+    } else if let Some(duplicates_params) = get_list_or_flag(&command, "duplicates") {
+        let extension = duplicates_params.first().map_or("json", String::as_str);
+        let format = extension.parse::<ReportFormat>()?;
+        let default_output = format!("duplicates.{}", extension);
+        let output = duplicates_params.get(1).unwrap_or(&default_output).to_owned();
+        info!("duplicates params format:{:?} output:{}", format, output);
+
+        tx.send(JobTask::DuplicatesTask(format, output)).unwrap();
+        drop(tx);
+    } else if command.get("gallery").unwrap_or(false) {
+        tx.send(JobTask::GalleryTask).unwrap();
+        drop(tx);
     } else {
+        let http = config.get_http();
+        if http.is_enabled() {
+            dashboard::start_http_server(&http, app.status.clone(), tx.clone())?;
+        }
+
+        if config.get_catch_up_missed_jobs() {
+            for task in scheduling::missed_jobs(&config, &app.history, Utc::now())? {
+                info!("catching up missed {}", task.name());
+                tx.send(task).unwrap();
+            }
+        }
+
+        config::watch_for_changes();
+
         let stop_flag = Arc::new(AtomicBool::new(false));
         let stop_flag_cloned = stop_flag.clone();
-        scheduling::run_job_scheduler(tx, stop_flag_cloned)?;
+        scheduling::run_job_scheduler(tx, stop_flag_cloned, app.status.clone())?;
+        health::start_watchdog(app.status.clone(), config.get_health());
     }
 
-    let res = run_task_receiver(&rx, app);
+    let res = run_task_receiver(&TaskQueue::start(rx), app, &runtime);
 
     match res {
         Err(err) => panic!("Error {}", err.to_string()),
@@ -223,113 +450,290 @@ fn main() -> CustomResult<()> {
 }
 
 pub struct MarkDownloadedPartition {
-    mark_downloaded: Vec<MediaItemId>,
+    mark_downloaded: Vec<(MediaItemId, FileDigest)>,
     unmark_downloaded: Vec<MediaItemId>
 }
 
-fn mark_unmark_downloaded_photos_in_fs(app: &mut App) -> CustomResult<()>
+async fn mark_unmark_downloaded_photos_in_fs(app: &mut App) -> CustomResult<()>
 {
-    let config = Config::new()?;
-    let downloaded = get_downloaded_files()?;
-    println!("Total # of files in fs: {}", downloaded.len());
+    let config = app.config.clone();
+    let fold_case = app.fold_case();
 
-    let partition = app.storage.partition_by_marked_download(&downloaded);
+    for destination in &app.destinations {
+        let replica = destination.replica();
+        let downloaded = get_downloaded_files(&*destination.backend, fold_case).await?;
+        info!("Total # of files in {}: {}", destination.name, downloaded.len());
 
-    if config.fix_downloaded_info.mark_downloaded {
-        println!("{} to be mark downloaded", partition.mark_downloaded.len());
-        app.storage.mark_downloaded(&partition.mark_downloaded);
-    }
+        let partition = app.storage.partition_by_marked_download(replica, &downloaded, fold_case);
 
-    if config.fix_downloaded_info.unmark_downloaded {
-        println!("{} to be unmark downloaded", partition.unmark_downloaded.len());
-        app.storage.unmark_downloaded(&partition.unmark_downloaded);
+        if config.fix_downloaded_info.mark_downloaded {
+            info!("{} to be mark downloaded in {}", partition.mark_downloaded.len(), destination.name);
+            app.storage.mark_downloaded(replica, &partition.mark_downloaded);
+        }
+
+        if config.fix_downloaded_info.unmark_downloaded {
+            info!("{} to be unmark downloaded in {}", partition.unmark_downloaded.len(), destination.name);
+            app.storage.unmark_downloaded(replica, &partition.unmark_downloaded);
+        }
     }
 
+    app.fix_filenames();
     app.storage.persist()?;
 
+    let max_age = Duration::from_secs(config.get_temp_file_max_age_hours() * 60 * 60);
+    let mut swept = SweepSummary::default();
+
+    for destination in &app.destinations {
+        let sweep = temp_files::sweep_temp_files(
+            &app.storage, &*destination.backend, destination.replica(), max_age, fold_case
+        ).await?;
+        info!("Temp files in {}: {} to resume, {} deleted, {} kept",
+              destination.name, sweep.resumable, sweep.deleted, sweep.kept
+        );
+        swept.add(&sweep);
+    }
+    app.startup_sweep = Some(swept);
+
     Ok(())
 }
 
-fn get_downloaded_files() -> CustomResult<Box<HashSet<FileName>>>
+async fn get_downloaded_files(backend: &dyn StorageBackend, fold_case: bool) -> CustomResult<Box<HashMap<FileName, u64>>>
 {
-    let config = Config::new()?;
-    let path = Path::new(config.storage_location.as_str());
+    let mut files = Box::new(HashMap::new());
 
-    let mut file_names = Box::new(HashSet::new());
-
-    if path.exists() {
-        for entry in path.read_dir()? {
-            if let Ok(entry) = entry {
-                if entry.file_type()?.is_file() {
-                    if let Some(file_name) = entry.file_name().into_string().ok() {
-                        file_names.insert(file_name);
-                    }
-                }
-            }
-        }
+    for object in backend.list().await? {
+        let file_name = if fold_case { object.name.to_lowercase() } else { object.name };
+        files.insert(file_name, object.size);
     }
 
-    Ok(file_names)
+    Ok(files)
 }
 
 struct App {
+    // the config snapshot of the running task
+    pub config: Arc<Config>,
+    pub client: reqwest::Client,
+    pub bandwidth: BandwidthLimiter,
     pub google_auth: GoogleAuthApi,
     pub photos_api: GooglePhotosApi,
     pub storage: StoredItemStore,
+    pub destinations: Vec<Destination>,
+    pub case_insensitive_fs: bool,
+    pub target_fs: TargetFs,
+    pub status: SharedStatus,
+    pub notifications: Notifications,
+    pub history: HistoryStore,
+    // reported with the first run that is recorded
+    pub startup_sweep: Option<SweepSummary>,
 }
 
 impl App {
-    pub fn search(&mut self, num_days_back: i32, limit_hint: usize) -> CustomResult<()> {
-        let media_items = self.photos_api.search(num_days_back, limit_hint)?;
-        println!("media items {}", media_items.len());
-        self.on_media_items(media_items)?;
+    fn fold_case(&self) -> bool {
+        self.case_insensitive_fs || self.target_fs.is_case_insensitive()
+    }
+
+    // Pages are stored as they arrive. With download set, new items of each page are downloaded
+    // right away using the baseUrl from search, while the next pages are being fetched.
+    pub async fn search(&mut self, window: SearchWindow, limit_hint: usize, download: bool) -> CustomResult<()> {
+        const SEARCH_PAGES_BUFFER: usize = 2;
+
+        let num_days_back = window.days_back(&self.config, &self.history, Utc::now());
+        match num_days_back {
+            Some(num_days_back) => info!("searching {} days back", num_days_back),
+            None => info!("searching the whole library"),
+        }
+
+        let mut pages = self.photos_api.search_pages(num_days_back, limit_hint);
+        let (tx, mut rx) = tokio::sync::mpsc::channel(SEARCH_PAGES_BUFFER);
+
+        let fetcher = tokio::spawn(async move {
+            while let Some(page) = pages.next_page().await? {
+                if tx.send(page).await.is_err() {
+                    break;
+                }
+            }
+
+            Ok::<bool, CustomError>(pages.truncated())
+        });
+
+        let space = self.space_guard()?;
+        let index = self.content_index()?;
+        let mut taken = TakenNames::of(&self.storage, self.fold_case());
+        let mut found = 0;
+        let mut downloaded = 0;
+
+        loop {
+            // a long sync or deep scan renews the token itself, the fetcher picks it up for its next page
+            self.refresh_token().await?;
+            let media_items = match rx.recv().await {
+                Some(media_items) => media_items,
+                None => break,
+            };
+            found += media_items.len();
+
+            let ids = extract_media_item_ids(&media_items);
+            let count = media_items.len();
+            let new = self.store_media_items(media_items);
+            self.status.lock().unwrap().record_found(count, new);
+
+            if download {
+                downloaded += self.download_listed(&ids, &mut taken, &space, &index).await?;
+            }
+        }
+
+        // the items past the limit are left to the next search, the window must not move past them
+        if fetcher.await?? {
+            warn!("search stopped at the limit of {} items", limit_hint);
+            self.status.lock().unwrap().record_truncated();
+        }
+
+        info!("media items {}, downloaded {}", found, downloaded);
+        if download {
+            self.record_pauses(&space);
+        }
+        self.fix_filenames();
+        self.storage.persist()?;
+
+        self.search_priority_albums(limit_hint).await?;
+
+        Ok(())
+    }
+
+    async fn download_listed(&mut self, ids: &[MediaItemId], taken: &mut TakenNames, space: &SpaceGuard, index: &ContentIndex)
+                             -> CustomResult<usize>
+    {
+        filenames::resolve_filenames_of(&mut self.storage, ids, taken, self.target_fs);
+
+        let replicas = destinations::replica_names(&self.destinations);
+        let not_downloaded = ids
+            .iter()
+            .filter(|id| self.storage.get(id).is_some_and(|item| !item.is_downloaded_everywhere(&replicas)))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let downloads = match space.all_paused() {
+            true => Downloads { copies: Vec::new(), skipped: not_downloaded.clone() },
+            false => {
+                let stored_items = self.get_stored_items_by_ids(&not_downloaded);
+                downloader::download(
+                    &self.client, &self.config, &self.bandwidth, space, &self.destinations, index, &stored_items
+                ).await?
+            }
+        };
+
+        self.status.lock().unwrap().record_downloads(&not_downloaded, &downloads);
+        self.storage.mark_copies(&downloads.copies);
+
+        Ok(downloads.copies.iter().map(|copy| &copy.id).collect::<HashSet<_>>().len())
+    }
+
+    // album membership is only needed to order downloads by album priority
+    async fn search_priority_albums(&mut self, limit_hint: usize) -> CustomResult<()> {
+        let config = self.config.clone();
+
+        if !config.get_download_order().contains(&DownloadOrder::AlbumPriority) {
+            return Ok(());
+        }
+
+        for album_id in config.priority_albums.clone().unwrap_or_default() {
+            self.refresh_token().await?;
+            let media_items = self.photos_api.search_album(&album_id, limit_hint).await?;
+            info!("album {} media items {}", album_id, media_items.len());
+
+            let ids = extract_media_item_ids(&media_items);
+            self.on_media_items(media_items)?;
+            self.storage.set_album_items(&album_id, &ids);
+        }
+
+        self.storage.persist()?;
 
         Ok(())
     }
 
-    pub fn download(&mut self, num_files: i32) -> CustomResult<()> {
-        const NUMBER_OF_FILES_PER_BATCH: i32 = 50;
+    fn content_index(&self) -> CustomResult<ContentIndex> {
+        let mode = self.config.dedup.unwrap_or_default();
+
+        Ok(ContentIndex::new(mode, &self.storage, &self.destinations))
+    }
 
-        let groups = num_files / NUMBER_OF_FILES_PER_BATCH;
-        let remainder = num_files % NUMBER_OF_FILES_PER_BATCH;
+    fn space_guard(&self) -> CustomResult<SpaceGuard> {
+        Ok(SpaceGuard::new(&self.config, &self.destinations, &self.storage))
+    }
+
+    pub async fn download(&mut self, num_files: i32) -> CustomResult<()> {
+        let batch_size = self.config.get_download_batch_size();
+        let space = self.space_guard()?;
+        let index = self.content_index()?;
+
+        space.check_all();
+        if space.all_paused() {
+            self.record_pauses(&space);
+            return Ok(());
+        }
+
+        let groups = num_files / batch_size;
+        let remainder = num_files % batch_size;
 
         for i in 0..groups {
-            println!("Split num of files {}x{}+{}, group {}",
-                     groups, NUMBER_OF_FILES_PER_BATCH, remainder, i
+            info!("Split num of files {}x{}+{}, group {}",
+                  groups, batch_size, remainder, i
             );
-            self.download_files(NUMBER_OF_FILES_PER_BATCH)?;
+            // a long download renews the token itself instead of leaving it to a RefreshTokenTask queued behind
+            self.refresh_token().await?;
+            self.download_files(batch_size, &space, &index).await?;
+
+            if space.all_paused() {
+                self.record_pauses(&space);
+                return Ok(());
+            }
         }
 
         if remainder > 0 {
-            self.download_files(remainder)?;
+            self.refresh_token().await?;
+            self.download_files(remainder, &space, &index).await?;
         }
 
+        self.record_pauses(&space);
+
         Ok(())
     }
 
-    fn download_files(&mut self, num_files: i32) -> CustomResult<()> {
-        let selected_stored_items = self.storage.select_files_for_download(num_files as usize);
+    // a destination stays paused on the dashboard until a later run finds room in it again
+    fn record_pauses(&self, space: &SpaceGuard) {
+        let pauses = space.pauses();
+        for reason in &pauses {
+            warn!("Download paused: {}", reason);
+        }
+
+        self.status.lock().unwrap().record_pauses(pauses);
+    }
+
+    async fn download_files(&mut self, num_files: i32, space: &SpaceGuard, index: &ContentIndex) -> CustomResult<()> {
+        let config = self.config.clone();
+        let download_order = config.get_download_order();
+        let priority_albums = config.priority_albums.clone().unwrap_or_default();
+        let ordering = DownloadOrdering {
+            order: &download_order,
+            priority_albums: &priority_albums,
+        };
+
+        let replicas = destinations::replica_names(&self.destinations);
+        let selected_stored_items = self.storage.select_files_for_download(num_files as usize, &ordering, &replicas);
         let selected_ids = extract_media_item_ids(&selected_stored_items);
 
-        println!("selected {}", selected_ids.len());
+        info!("selected {}", selected_ids.len());
         let updated_media_items =
-            self.photos_api.batch_get(&selected_ids)?;
+            self.photos_api.batch_get(&selected_ids).await?;
 
         let updated_ids = extract_media_item_ids(&updated_media_items);
         let stored_items = self.get_stored_items_by_ids(&updated_ids);
 
-        let downloaded_ids = downloader::download(&stored_items)?;
-
-        let hash: HashSet<&MediaItemId> = HashSet::from_iter(downloaded_ids.iter());
-        let mark_downloaded = updated_media_items
-            .iter()
-            .filter(|item| {
-                hash.contains(&item.id.to_owned())
-            })
-            .map(|item| { item.get_media_item_id() })
-            .collect::<Vec<_>>();
+        let downloads = downloader::download(
+            &self.client, &self.config, &self.bandwidth, space, &self.destinations, index, &stored_items
+        ).await?;
 
-        self.storage.mark_downloaded(&mark_downloaded);
+        self.status.lock().unwrap().record_downloads(&updated_ids, &downloads);
+        self.storage.mark_copies(&downloads.copies);
         self.on_media_items(updated_media_items)?;
 
         Ok(())
@@ -348,6 +752,18 @@ impl App {
     }
 
     fn on_media_items(&mut self, media_items: Vec<MediaItem>) -> CustomResult<()> {
+        self.store_media_items(media_items);
+
+        self.fix_filenames();
+        self.storage.persist()?;
+
+        Ok(())
+    }
+
+    // returns how many of the media items were not in the catalog yet
+    fn store_media_items(&mut self, media_items: Vec<MediaItem>) -> usize {
+        let mut new = 0;
+
         for media_item in media_items {
             let id = media_item.get_media_item_id();
 
@@ -361,61 +777,152 @@ impl App {
                         mediaItem: media_item,
                         appData: None,
                         alt_filename: None,
+                        albums: None,
+                        perceptual: None,
                     },
                 );
+                new += 1;
             }
         }
 
-        self.fix_duplicate_filenames();
-        self.storage.persist()?;
+        new
+    }
 
-        Ok(())
+    fn fix_filenames(&mut self) {
+        let fold_case = self.fold_case();
+        let renamed = filenames::resolve_filenames(&mut self.storage, self.target_fs, fold_case);
+        info!("Renamed {} duplicate or invalid file names", renamed);
     }
 
-    fn fix_duplicate_filenames(&mut self) {
-        type Filename = String;
-        let mut map: HashMap<Filename, Vec<MediaItemId>> = HashMap::new();
+    pub async fn verify(&mut self, unmark: bool) -> CustomResult<()> {
+        for destination in &self.destinations {
+            let report = integrity::verify_library(
+                &mut self.storage, &*destination.backend, destination.replica(), unmark
+            ).await?;
 
-        for stored_item in self.storage.get_all() {
-            let filename = stored_item.mediaItem.filename.to_owned();
+            info!("Verified {} files in {}, hashed {} for the first time",
+                  report.checked, destination.name, report.hashed
+            );
+            info!("{} missing, {} truncated, {} modified",
+                  report.missing.len(), report.truncated.len(), report.modified.len()
+            );
 
-            if let Some(ids) = map.get_mut(&filename) {
-                ids.push(filename);
-            } else {
-                map.insert(filename, vec![stored_item.get_media_item_id()]);
+            for (state, ids) in [("missing", &report.missing), ("truncated", &report.truncated), ("modified", &report.modified)] {
+                for id in ids {
+                    if let Some(item) = self.storage.get(id) {
+                        warn!("{} {}", state, item.get_filename());
+                    }
+                }
+            }
+
+            if unmark {
+                info!("{} unmarked for re-download", report.broken().len());
             }
         }
 
-        let dups = map
-            .iter()
-            .filter(|&(_, v)| {
-                v.len() > 1
-            }).collect::<HashMap<_, _>>();
-
-        let dup_size = dups.len();
-        println!("Found {} duplicate file names", dup_size);
-
-        for (_, id) in dups {
-            let mut i = 0;
-            while i < id.len() {
-                let id = id.get(i).unwrap();
-                if let Some(mut item) = self.storage.get_cloned(&id) {
-                    if item.alt_filename.is_none() {
-                        item.alt_filename = Some(format!("{}_{}", i, item.mediaItem.filename));
-                        self.storage.set(&item.get_media_item_id(), item);
-                    }
-                }
+        Ok(())
+    }
+
+    pub async fn dedup(&mut self) -> CustomResult<()> {
+        let mode = self.config.dedup.unwrap_or_default();
+
+        if mode == DedupMode::Off {
+            warn!("dedup is off, set \"dedup\" in config.json to hardlink or reflink");
+            return Ok(());
+        }
+
+        for destination in &self.destinations {
+            let report = dedup::dedup_library(&mut self.storage, destination, mode).await?;
+            info!("Linked {} duplicate files in {}, {} bytes saved",
+                  report.linked.len(), destination.name, report.bytes_saved
+            );
+        }
+
+        Ok(())
+    }
+
+    // photos are hashed from the first destination, hashes of unchanged files come from the catalog
+    pub async fn duplicates(&mut self, format: ReportFormat, output: &str) -> CustomResult<()> {
+        let config = self.config.clone();
+        let destination = &self.destinations[0];
+
+        let report = perceptual::hash_library(
+            &mut self.storage, destination, config.get_download_files_parallel() as usize
+        ).await?;
+        info!("Perceptual hashes: {} computed, {} cached, {} failed", report.hashed, report.cached, report.failed);
+
+        let downloaded = self.storage.get_all()
+            .into_iter()
+            .filter(|stored_item| stored_item.get_download_info_at(destination.replica()).is_some())
+            .collect::<Vec<_>>();
+        let clusters = perceptual::find_clusters(&downloaded, config.get_duplicates_max_distance());
+
+        perceptual::write_report(&self.storage, &clusters, format, Path::new(output))?;
+        info!("{} clusters of near-duplicates written to {}", clusters.len(), output);
 
-                i += 1;
+        Ok(())
+    }
+
+    // the gallery links to the originals, so it is built from the first destination on a local disk
+    pub async fn gallery(&self) -> CustomResult<()> {
+        let config = self.config.clone();
+        let destination = self.destinations
+            .iter()
+            .find(|destination| destination.backend.local_root().is_some())
+            .ok_or_else(|| CustomError::Err("the gallery needs a destination on a local disk".to_owned()))?;
+        let gallery_dir = config.get_gallery_dir();
+
+        let album_ids = self.storage.get_all()
+            .into_iter()
+            .flat_map(|stored_item| stored_item.albums.iter().flatten())
+            .collect::<HashSet<_>>();
+        let mut album_titles = HashMap::new();
+        for album_id in album_ids {
+            match self.photos_api.get_album_title(album_id).await {
+                Ok(Some(title)) => { album_titles.insert(album_id.to_owned(), title); }
+                Ok(None) => {}
+                Err(e) => warn!("Cannot get the title of album {}, showing its id {}", album_id, e),
             }
         }
+
+        let report = gallery::generate_gallery(
+            &self.storage, destination, Path::new(&gallery_dir), &album_titles, config.get_thumbnail_size(),
+            config.get_download_files_parallel() as usize
+        ).await?;
+
+        info!("Gallery of {} files written to {}, {} pages", report.items, gallery_dir, report.pages);
+        info!("Thumbnails: {} created, {} cached, {} failed", report.thumbnails, report.cached, report.failed);
+
+        Ok(())
     }
 
-    pub fn refresh_token(&mut self) -> CustomResult<()> {
-        self.google_auth.authenticate_or_renew()?;
+    pub async fn refresh_token(&mut self) -> CustomResult<()> {
+        let token = self.google_auth.authenticate_or_renew().await?;
+        self.photos_api.set_token(token);
 
         Ok(())
     }
+
+    // a reload applies from the next task on
+    fn use_config(&mut self, config: Arc<Config>) {
+        if Arc::ptr_eq(&self.config, &config) {
+            return;
+        }
+
+        match BandwidthLimiter::new(&config.bandwidth_limit) {
+            Ok(bandwidth) => self.bandwidth = bandwidth,
+            Err(e) => error!("Error applying bandwidth_limit {}", e),
+        }
+        self.config = config;
+    }
+
+    fn update_status(&self) {
+        let replicas = destinations::replica_names(&self.destinations);
+        let mut status = self.status.lock().unwrap();
+
+        status.update_totals(&self.storage, &replicas);
+        status.token_expires_at = self.google_auth.token.as_ref().map(|token| token.expires_at());
+    }
 }
 
 trait HasMediaItemId {
@@ -428,6 +935,13 @@ fn extract_media_item_ids<T>(items: &Vec<T>) -> Vec<MediaItemId>
     items.iter().map(|item| item.get_media_item_id()).collect::<Vec<_>>()
 }
 
+impl MediaItem {
+    // catalogs from before productUrl was stored fall back to the same link built from the id
+    fn get_product_url(&self) -> String {
+        self.productUrl.clone().unwrap_or_else(|| format!("https://photos.google.com/lr/photo/{}", self.id))
+    }
+}
+
 impl HasMediaItemId for MediaItem {
     fn get_media_item_id(&self) -> MediaItemId {
         self.id.to_owned()
@@ -440,23 +954,181 @@ impl HasMediaItemId for StoredItem {
     }
 }
 
-fn run_task_receiver(rx: &Receiver<JobTask>, mut app: App) -> CustomResult<()> {
-    for r in rx {
-        match r {
-            JobTask::RefreshTokenTask => {
-                println!("RefreshTokenTask");
-                app.refresh_token()?;
+// A failed task is recorded and the next one still runs, so /healthz and /readyz can report it while the
+// daemon keeps going. The last error is returned once the channel closes.
+fn run_task_receiver(queue: &TaskQueue, mut app: App, runtime: &tokio::runtime::Runtime) -> CustomResult<()> {
+    let mut last_error = None;
+    app.update_status();
+    app.status.lock().unwrap().catalog_loaded_at = Some(Utc::now());
+
+    while let Some(r) = queue.next() {
+        app.use_config(config::current());
+        let task = r.name();
+        let params = r.params();
+        let run_id = logging::start_run(task);
+        info!("{} run {}, pending {:?}", task, run_id, queue.pending());
+        app.status.lock().unwrap().task_started(task);
+        if task != JobTask::RefreshTokenTask.name() {
+            if let Some(sweep) = app.startup_sweep.take() {
+                app.status.lock().unwrap().record_sweep(sweep);
             }
-            JobTask::DownloadFilesTask(num_files) => {
-                println!("DownloadFilesTask");
-                app.download(num_files)?;
+        }
+        let started_at = Utc::now();
+        let bytes_before = METRICS.downloaded_bytes();
+
+        let res = match r {
+            JobTask::RefreshTokenTask => runtime.block_on(app.refresh_token()),
+            JobTask::DownloadFilesTask(num_files) => runtime.block_on(app.download(num_files)),
+            JobTask::SearchFilesTask(window, limit_hint) => {
+                runtime.block_on(app.search(window, limit_hint, false))
+            }
+            JobTask::SyncFilesTask(window, limit_hint) => {
+                runtime.block_on(app.search(window, limit_hint, true))
             }
-            JobTask::SearchFilesTask(num_days_back, limit_hint) => {
-                println!("SearchFilesTask");
-                app.search(num_days_back, limit_hint)?;
+            JobTask::DeepScanTask => runtime.block_on(app.search(SearchWindow::All, usize::MAX, false)),
+            JobTask::VerifyFilesTask(unmark) => runtime.block_on(app.verify(unmark)),
+            JobTask::DedupFilesTask => runtime.block_on(app.dedup()),
+            JobTask::DuplicatesTask(format, output) => runtime.block_on(app.duplicates(format, &output)),
+            JobTask::GalleryTask => runtime.block_on(app.gallery()),
+            JobTask::DigestTask => runtime.block_on(app.notifications.send_digest(&app.client, &app.config)),
+        };
+
+        app.update_status();
+        let counts = app.status.lock().unwrap().run_counts.clone();
+        app.status.lock().unwrap().task_finished(task, &res);
+
+        if task != "DigestTask" {
+            let report = RunReport::new(app.config.get_profile(), task, &params, &run_id, started_at, &counts, METRICS.downloaded_bytes() - bytes_before, &res);
+            info!("{}", report.summary());
+
+            // token refreshes run every 30 s and would fill the history and the digest, they are only logged
+            if task != JobTask::RefreshTokenTask.name() {
+                app.history.record(report.clone(), app.config.get_history_max_runs());
+                if let Err(e) = app.history.persist() {
+                    error!("Error saving job history {}", e);
+                }
+                if let Err(e) = runtime.block_on(app.notifications.notify(&app.client, &app.config, report)) {
+                    error!("Error sending notifications {}", e);
+                }
             }
         }
+
+        if let Err(e) = res {
+            error!("{} failed {}", task, e);
+            last_error = Some(e);
+        }
+        logging::end_run();
     }
 
-    Ok(())
+    last_error.map_or(Ok(()), Err)
+}
+
+#[cfg(test)]
+pub mod test {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    // a photo in the catalog, taken on 2019-01-01 12:00 unless changed
+    pub struct StoredItemBuilder {
+        stored_item: StoredItem,
+    }
+
+    impl StoredItemBuilder {
+        pub fn new(id: &str, filename: &str) -> StoredItemBuilder {
+            StoredItemBuilder {
+                stored_item: StoredItem {
+                    mediaItem: MediaItem {
+                        id: id.to_owned(),
+                        productUrl: None,
+                        baseUrl: String::new(),
+                        filename: filename.to_owned(),
+                        mediaMetadata: MediaMetaData {
+                            creationTime: Utc.with_ymd_and_hms(2019, 1, 1, 12, 0, 0).unwrap(),
+                            width: None,
+                            height: None,
+                            photo: Some(Photo {}),
+                            video: None,
+                        },
+                    },
+                    appData: None,
+                    alt_filename: None,
+                    albums: None,
+                    perceptual: None,
+                },
+            }
+        }
+
+        pub fn created_on(mut self, day: u32) -> StoredItemBuilder {
+            self.stored_item.mediaItem.mediaMetadata.creationTime = Utc.with_ymd_and_hms(2019, 1, day, 12, 0, 0).unwrap();
+            self
+        }
+
+        pub fn video(mut self) -> StoredItemBuilder {
+            let meta = &mut self.stored_item.mediaItem.mediaMetadata;
+            meta.photo = None;
+            meta.video = Some(Video {});
+            self
+        }
+
+        pub fn dimensions(mut self, width: u32, height: u32) -> StoredItemBuilder {
+            let meta = &mut self.stored_item.mediaItem.mediaMetadata;
+            meta.width = Some(width.to_string());
+            meta.height = Some(height.to_string());
+            self
+        }
+
+        pub fn albums(mut self, albums: &[&str]) -> StoredItemBuilder {
+            self.stored_item.albums = Some(albums.iter().map(|album| album.to_string()).collect());
+            self
+        }
+
+        // downloaded to the primary destination on 2019-02-01
+        pub fn downloaded(mut self, size: Option<u64>, sha256: Option<&str>) -> StoredItemBuilder {
+            self.stored_item.appData = Some(AppData {
+                download_info: Some(DownloadInfo {
+                    downloaded_at: Utc.with_ymd_and_hms(2019, 2, 1, 12, 0, 0).unwrap(),
+                    size,
+                    sha256: sha256.map(str::to_owned),
+                    linked_to: None,
+                }),
+                replicas: None,
+            });
+            self
+        }
+
+        pub fn build(self) -> StoredItem {
+            self.stored_item
+        }
+
+        pub fn add_to(self, storage: &mut StoredItemStore) {
+            let stored_item = self.build();
+            storage.set(&stored_item.mediaItem.id.to_owned(), stored_item);
+        }
+    }
+
+    fn parse(args: &str) -> Commander {
+        parse_args(args.split(' ').map(str::to_owned).collect())
+    }
+
+    #[test]
+    pub fn options_are_read_from_the_command_line() {
+        let command = parse("rs-google-photos-sync --target_fs windows --set http.metrics=true profile=nas --sync 3 100");
+
+        assert_eq!(command.get_str("target_fs"), Some("windows".to_owned()));
+        assert_eq!(command.get_list("set"), Some(vec!["http.metrics=true".to_owned(), "profile=nas".to_owned()]));
+        assert_eq!(command.get_list("sync"), Some(vec!["3".to_owned(), "100".to_owned()]));
+        assert_eq!(command.get_str("config"), None);
+
+        let command = parse("rs-google-photos-sync --target-fs exfat --gallery");
+        assert_eq!(command.get_str("target_fs"), Some("exfat".to_owned()));
+        assert_eq!(command.get("gallery"), Some(true));
+    }
+
+    #[test]
+    pub fn a_list_option_without_values_is_an_empty_list() {
+        assert_eq!(get_list_or_flag(&parse("rs-google-photos-sync --duplicates"), "duplicates"), Some(vec![]));
+        assert_eq!(get_list_or_flag(&parse("rs-google-photos-sync --duplicates html"), "duplicates"), Some(vec!["html".to_owned()]));
+        assert_eq!(get_list_or_flag(&parse("rs-google-photos-sync --gallery"), "duplicates"), None);
+    }
 }
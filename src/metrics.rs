@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+
+use crate::status::Status;
+
+const PREFIX: &str = "rs_google_photos_sync";
+
+// Counters since the process started, the gauges come from Status when rendered
+pub struct Metrics {
+    downloaded_bytes: AtomicU64,
+    download_errors: Mutex<BTreeMap<&'static str, u64>>,
+    api_calls: Mutex<BTreeMap<&'static str, u64>>,
+    rate_limited: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    pub const fn new() -> Metrics {
+        Metrics {
+            downloaded_bytes: AtomicU64::new(0),
+            download_errors: Mutex::new(BTreeMap::new()),
+            api_calls: Mutex::new(BTreeMap::new()),
+            rate_limited: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn add_downloaded_bytes(&self, bytes: u64) {
+        self.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn downloaded_bytes(&self) -> u64 {
+        self.downloaded_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn download_error(&self, kind: &'static str) {
+        *self.download_errors.lock().unwrap().entry(kind).or_insert(0) += 1;
+    }
+
+    // every request to Google counts as a call, status is None when it got no response
+    pub fn api_call(&self, endpoint: &'static str, status: Option<StatusCode>) {
+        *self.api_calls.lock().unwrap().entry(endpoint).or_insert(0) += 1;
+
+        if status == Some(StatusCode::TOO_MANY_REQUESTS) {
+            *self.rate_limited.lock().unwrap().entry(endpoint).or_insert(0) += 1;
+        }
+    }
+
+    // Prometheus text exposition format
+    pub fn render(&self, profile: &str, status: &Status, now: DateTime<Utc>) -> String {
+        let mut out = String::new();
+        let profile = format!("profile=\"{}\"", escape_label(profile));
+
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+            let _ = writeln!(out, "# TYPE {}_{} {}", PREFIX, name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}_{}{{{}{}}} {}", PREFIX, name, profile, labels, value);
+            }
+        };
+        let labelled = |label: &str, values: &BTreeMap<&'static str, u64>| {
+            values.iter()
+                .map(|(key, value)| (format!(",{}=\"{}\"", label, escape_label(key)), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        let totals = &status.totals;
+        metric("catalog_items", "gauge", "Media items in the catalog",
+               vec![(String::new(), totals.searched.to_string())]);
+        metric("downloaded_items", "gauge", "Media items downloaded to every destination",
+               vec![(String::new(), totals.downloaded.to_string())]);
+        metric("pending_items", "gauge", "Media items not downloaded yet",
+               vec![(String::new(), totals.pending.to_string())]);
+        metric("downloaded_bytes_total", "counter", "Bytes downloaded from Google",
+               vec![(String::new(), self.downloaded_bytes().to_string())]);
+        metric("download_errors_total", "counter", "Failed downloads by kind",
+               labelled("kind", &self.download_errors.lock().unwrap()));
+        metric("api_calls_total", "counter", "Requests to Google by endpoint",
+               labelled("endpoint", &self.api_calls.lock().unwrap()));
+        metric("api_rate_limited_total", "counter", "Requests to Google answered with 429 by endpoint",
+               labelled("endpoint", &self.rate_limited.lock().unwrap()));
+
+        if let Some(expires_at) = status.token_expires_at {
+            metric("token_expiry_seconds", "gauge", "Seconds until the access token expires, negative once expired",
+                   vec![(String::new(), expires_at.signed_duration_since(now).num_seconds().to_string())]);
+        }
+
+        let mut last_success = status.last_success.iter().collect::<Vec<_>>();
+        last_success.sort();
+        metric("last_success_timestamp_seconds", "gauge", "Unix time of the last successful run by task",
+               last_success.into_iter()
+                   .map(|(task, at)| (format!(",task=\"{}\"", escape_label(task)), at.timestamp().to_string()))
+                   .collect());
+
+        metric("download_paused", "gauge", "Destinations the last download run paused for disk space or the library quota",
+               status.download_paused.iter()
+                   .map(|reason| (format!(",destination=\"{}\"", escape_label(reason.destination())), "1".to_owned()))
+                   .collect());
+
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+    use crate::disk_space::PauseReason;
+
+    #[test]
+    pub fn render_labels_every_sample_with_the_profile() {
+        let now = Utc.with_ymd_and_hms(2020, 5, 1, 12, 0, 0).unwrap();
+        let metrics = Metrics::new();
+        metrics.add_downloaded_bytes(1024);
+        metrics.download_error("network");
+        metrics.download_error("network");
+        metrics.api_call("search", Some(StatusCode::TOO_MANY_REQUESTS));
+        metrics.api_call("search", None);
+
+        let mut status = Status::default();
+        status.totals.searched = 3;
+        status.token_expires_at = Some(now + Duration::seconds(90));
+        status.last_success.insert("DownloadFilesTask".to_owned(), now);
+        status.record_pauses(vec![PauseReason::LowDiskSpace { destination: "usb".to_owned(), available: 10, reserve: 100 }]);
+
+        let text = metrics.render("home \"nas\"", &status, now);
+
+        assert!(text.contains("# TYPE rs_google_photos_sync_catalog_items gauge\n"));
+        assert!(text.contains("rs_google_photos_sync_catalog_items{profile=\"home \\\"nas\\\"\"} 3\n"));
+        assert!(text.contains("rs_google_photos_sync_downloaded_bytes_total{profile=\"home \\\"nas\\\"\"} 1024\n"));
+        assert!(text.contains("_download_errors_total{profile=\"home \\\"nas\\\"\",kind=\"network\"} 2\n"));
+        assert!(text.contains("_api_calls_total{profile=\"home \\\"nas\\\"\",endpoint=\"search\"} 2\n"));
+        assert!(text.contains("_api_rate_limited_total{profile=\"home \\\"nas\\\"\",endpoint=\"search\"} 1\n"));
+        assert!(text.contains("_token_expiry_seconds{profile=\"home \\\"nas\\\"\"} 90\n"));
+        assert!(text.contains(&format!("_last_success_timestamp_seconds{{profile=\"home \\\"nas\\\"\",task=\"DownloadFilesTask\"}} {}\n", now.timestamp())));
+        assert!(text.contains("_download_paused{profile=\"home \\\"nas\\\"\",destination=\"usb\"} 1\n"));
+    }
+}
@@ -6,6 +6,7 @@ use std::path::Path;
 use chrono::{DateTime, Utc};
 use serde::{de::DeserializeOwned, Serialize};
 use serde_json;
+use log::info;
 
 use crate::error::CustomResult;
 
@@ -100,7 +101,7 @@ impl<T> KeyValueStore<T>
             self.data = serde_json::from_str(&data)?;
         }
 
-        println!("loaded {} stored items", self.data.len());
+        info!("loaded {} stored items", self.data.len());
 
         Ok(())
     }
@@ -0,0 +1,470 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use lettre::{Message, SmtpTransport, Transport};
+use lettre::transport::smtp::authentication::Credentials;
+use log::{error, info};
+
+use crate::config::Config;
+use crate::disk_space::PauseReason;
+use crate::error::{CustomError, CustomResult};
+use crate::status::RunCounts;
+use crate::temp_files::SweepSummary;
+
+// reports kept for the digest, older ones are dropped
+const MAX_DIGEST_REPORTS: usize = 1000;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct NotifyConfig {
+    pub notifiers: Option<Vec<NotifierConfig>>,
+    // when notifiers with "when": "digest" get the reports collected since the last digest
+    pub digest_schedule: Option<String>,
+}
+
+impl NotifyConfig {
+    pub fn get_notifiers(&self) -> Vec<NotifierConfig> {
+        self.notifiers.clone().unwrap_or_default()
+    }
+
+    pub fn get_digest_schedule(&self) -> String {
+        self.digest_schedule.clone().unwrap_or_else(|| "0 0 8 * * *".to_owned())
+    }
+
+    pub fn has_digest(&self) -> bool {
+        self.get_notifiers().iter().any(|notifier| notifier.when.unwrap_or_default() == NotifyWhen::Digest)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NotifierConfig {
+    #[serde(flatten)]
+    pub kind: NotifierKind,
+    pub when: Option<NotifyWhen>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotifierKind {
+    // POSTs the report as JSON
+    Webhook { url: String },
+    Smtp {
+        server: String,
+        port: Option<u16>,
+        security: Option<SmtpSecurity>,
+        username: Option<String>,
+        password: Option<String>,
+        from: String,
+        to: Vec<String>,
+    },
+    Ntfy { server: String, topic: String, token: Option<String> },
+    Gotify { server: String, token: String },
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSecurity {
+    // plain text, for a local relay or MailHog
+    None,
+    #[default]
+    Starttls,
+    Tls,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyWhen {
+    Always,
+    // runs that found, downloaded or failed something
+    OnChange,
+    #[default]
+    OnFailure,
+    Digest,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunReport {
+    pub profile: String,
+    pub task: String,
+    pub params: String,
+    pub run_id: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub duration_seconds: i64,
+    pub ok: bool,
+    pub error: Option<String>,
+    pub found: usize,
+    pub new: usize,
+    pub downloaded: usize,
+    pub failed: usize,
+    #[serde(default)]
+    pub skipped: usize,
+    #[serde(default)]
+    pub paused: Vec<PauseReason>,
+    pub bytes: u64,
+    #[serde(default)]
+    pub truncated: bool,
+    #[serde(default)]
+    pub temp_files: Option<SweepSummary>,
+}
+
+impl RunReport {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(profile: String, task: &str, params: &str, run_id: &str, started_at: DateTime<Utc>, counts: &RunCounts, bytes: u64,
+               res: &CustomResult<()>) -> RunReport {
+        let finished_at = Utc::now();
+
+        RunReport {
+            profile,
+            task: task.to_owned(),
+            params: params.to_owned(),
+            run_id: run_id.to_owned(),
+            started_at,
+            finished_at,
+            duration_seconds: finished_at.signed_duration_since(started_at).num_seconds(),
+            ok: res.is_ok(),
+            error: res.as_ref().err().map(|e| e.to_string()),
+            found: counts.found,
+            new: counts.new,
+            downloaded: counts.downloaded,
+            failed: counts.failed,
+            skipped: counts.skipped,
+            paused: counts.paused.clone(),
+            bytes,
+            truncated: counts.truncated,
+            temp_files: counts.temp_files.clone(),
+        }
+    }
+
+    // a task that finished but could not download some items counts as failed too
+    pub fn is_failure(&self) -> bool {
+        !self.ok || self.failed > 0
+    }
+
+    fn is_change(&self) -> bool {
+        self.is_failure() || self.new > 0 || self.downloaded > 0 || !self.paused.is_empty()
+    }
+
+    pub fn summary(&self) -> String {
+        let outcome = match &self.error {
+            Some(error) => format!("failed: {}", error),
+            None => "ok".to_owned(),
+        };
+
+        let temp_files = match &self.temp_files {
+            Some(sweep) => format!(", temp files: {} to resume, {} deleted, {} kept", sweep.resumable, sweep.deleted, sweep.kept),
+            None => String::new(),
+        };
+
+        let paused = match self.paused.is_empty() {
+            true => String::new(),
+            false => format!(", skipped {}, paused: {}", self.skipped,
+                             self.paused.iter().map(PauseReason::to_string).collect::<Vec<_>>().join("; ")),
+        };
+
+        format!("{} {} in {}s, found {}, new {}, downloaded {}, failed {}, {} bytes{}{}{}",
+                self.task, outcome, self.duration_seconds, self.found, self.new, self.downloaded, self.failed, self.bytes,
+                if self.truncated { ", stopped at the search limit" } else { "" }, paused, temp_files)
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Notification<'a> {
+    Run(&'a RunReport),
+    Digest { profile: String, reports: &'a [RunReport] },
+}
+
+impl<'a> Notification<'a> {
+    fn is_failure(&self) -> bool {
+        match self {
+            Notification::Run(report) => report.is_failure(),
+            Notification::Digest { reports, .. } => reports.iter().any(|report| report.is_failure()),
+        }
+    }
+
+    fn title(&self) -> String {
+        match self {
+            Notification::Run(report) => {
+                let outcome = if report.is_failure() { "failed" } else { "ok" };
+                format!("rs-google-photos-sync {}: {} {}", report.profile, report.task, outcome)
+            }
+            Notification::Digest { profile, reports } => {
+                let failed = reports.iter().filter(|report| report.is_failure()).count();
+                format!("rs-google-photos-sync {}: {} runs, {} failed", profile, reports.len(), failed)
+            }
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            Notification::Run(report) => report.summary(),
+            Notification::Digest { reports, .. } => {
+                let downloaded = reports.iter().map(|report| report.downloaded).sum::<usize>();
+                let bytes = reports.iter().map(|report| report.bytes).sum::<u64>();
+                let mut text = format!("downloaded {}, {} bytes\n", downloaded, bytes);
+
+                for report in reports.iter() {
+                    text.push_str(&format!("{} {}\n", report.started_at.format("%Y-%m-%d %H:%M"), report.summary()));
+                }
+
+                text
+            }
+        }
+    }
+}
+
+// Sends run reports to the configured notifiers and keeps them for the digest.
+// A notifier that cannot be reached is logged, it never fails the task.
+#[derive(Default)]
+pub struct Notifications {
+    digest: Vec<RunReport>,
+}
+
+impl Notifications {
+    pub async fn notify(&mut self, client: &reqwest::Client, config: &Config, report: RunReport) -> CustomResult<()> {
+        let notifiers = config.get_notify().get_notifiers();
+
+        for notifier in notifiers.iter() {
+            if should_send(notifier.when.unwrap_or_default(), &report) {
+                send(client, &notifier.kind, &Notification::Run(&report)).await;
+            }
+        }
+
+        if notifiers.iter().any(|notifier| notifier.when == Some(NotifyWhen::Digest)) {
+            if self.digest.len() == MAX_DIGEST_REPORTS {
+                self.digest.remove(0);
+            }
+            self.digest.push(report);
+        }
+
+        Ok(())
+    }
+
+    pub async fn send_digest(&mut self, client: &reqwest::Client, config: &Config) -> CustomResult<()> {
+        if self.digest.is_empty() {
+            info!("no runs since the last digest");
+            return Ok(());
+        }
+
+        let notification = Notification::Digest { profile: config.get_profile(), reports: &self.digest };
+        for notifier in config.get_notify().get_notifiers() {
+            if notifier.when == Some(NotifyWhen::Digest) {
+                send(client, &notifier.kind, &notification).await;
+            }
+        }
+
+        self.digest.clear();
+
+        Ok(())
+    }
+}
+
+fn should_send(when: NotifyWhen, report: &RunReport) -> bool {
+    match when {
+        NotifyWhen::Always => true,
+        NotifyWhen::OnChange => report.is_change(),
+        NotifyWhen::OnFailure => report.is_failure(),
+        NotifyWhen::Digest => false,
+    }
+}
+
+async fn send(client: &reqwest::Client, kind: &NotifierKind, notification: &Notification<'_>) {
+    let res = match kind {
+        NotifierKind::Webhook { url } => send_webhook(client, url, notification).await,
+        NotifierKind::Smtp { .. } => send_email(kind.clone(), notification.title(), notification.text()).await,
+        NotifierKind::Ntfy { server, topic, token } => send_ntfy(client, server, topic, token, notification).await,
+        NotifierKind::Gotify { server, token } => send_gotify(client, server, token, notification).await,
+    };
+
+    if let Err(e) = res {
+        error!("Error sending notification {}", e);
+    }
+}
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+async fn send_webhook(client: &reqwest::Client, url: &str, notification: &Notification<'_>) -> CustomResult<()> {
+    client.post(url).timeout(TIMEOUT).json(notification).send().await?.error_for_status()?;
+
+    Ok(())
+}
+
+async fn send_ntfy(client: &reqwest::Client, server: &str, topic: &str, token: &Option<String>, notification: &Notification<'_>) -> CustomResult<()> {
+    let mut request = client.post(format!("{}/{}", server.trim_end_matches('/'), topic))
+        .timeout(TIMEOUT)
+        .header("Title", notification.title())
+        .header("Priority", if notification.is_failure() { "high" } else { "default" })
+        .body(notification.text());
+
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+
+    request.send().await?.error_for_status()?;
+
+    Ok(())
+}
+
+async fn send_gotify(client: &reqwest::Client, server: &str, token: &str, notification: &Notification<'_>) -> CustomResult<()> {
+    let body = serde_json::json!({
+        "title": notification.title(),
+        "message": notification.text(),
+        "priority": if notification.is_failure() { 8 } else { 4 },
+    });
+
+    client.post(format!("{}/message", server.trim_end_matches('/')))
+        .timeout(TIMEOUT)
+        .header("X-Gotify-Key", token)
+        .json(&body)
+        .send().await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+// lettre's SMTP transport blocks
+async fn send_email(kind: NotifierKind, subject: String, body: String) -> CustomResult<()> {
+    tokio::task::spawn_blocking(move || send_email_blocking(&kind, subject, body)).await?
+}
+
+fn send_email_blocking(kind: &NotifierKind, subject: String, body: String) -> CustomResult<()> {
+    let (server, port, security, username, password, from, to) = match kind {
+        NotifierKind::Smtp { server, port, security, username, password, from, to } =>
+            (server, port, security.unwrap_or_default(), username, password, from, to),
+        _ => return Err(CustomError::Err("not an smtp notifier".to_owned())),
+    };
+    let smtp_error = |e: lettre::transport::smtp::Error| CustomError::Err(format!("smtp {} {}", server, e));
+    let address_error = |e: lettre::address::AddressError| CustomError::Err(format!("email address {}", e));
+
+    let mut message = Message::builder()
+        .from(from.parse().map_err(address_error)?)
+        .subject(subject);
+    for to in to {
+        message = message.to(to.parse().map_err(address_error)?);
+    }
+    let message = message.body(body).map_err(|e| CustomError::Err(format!("email {}", e)))?;
+
+    let mut transport = match security {
+        SmtpSecurity::None => SmtpTransport::builder_dangerous(server),
+        SmtpSecurity::Starttls => SmtpTransport::starttls_relay(server).map_err(smtp_error)?,
+        SmtpSecurity::Tls => SmtpTransport::relay(server).map_err(smtp_error)?,
+    }.timeout(Some(TIMEOUT));
+
+    if let Some(port) = port {
+        transport = transport.port(*port);
+    }
+    if let (Some(username), Some(password)) = (username, password) {
+        transport = transport.credentials(Credentials::new(username.to_owned(), password.to_owned()));
+    }
+
+    transport.build().send(&message).map_err(smtp_error)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    fn report(ok: bool, new: usize, failed: usize) -> RunReport {
+        let counts = RunCounts { found: 10, new, downloaded: new, failed, ..RunCounts::default() };
+        let res = if ok { Ok(()) } else { Err(CustomError::Err("timeout".to_owned())) };
+
+        RunReport::new("nas".to_owned(), "SearchFilesTask", "days_back=10 limit=999999", "5eb3f2000001", Utc::now(), &counts, 2048, &res)
+    }
+
+    #[test]
+    pub fn thresholds_decide_which_reports_are_sent() {
+        let quiet = report(true, 0, 0);
+        let new_items = report(true, 3, 0);
+        let failed_items = report(true, 3, 1);
+        let failed = report(false, 0, 0);
+
+        assert!(should_send(NotifyWhen::Always, &quiet));
+        assert!(!should_send(NotifyWhen::OnChange, &quiet));
+        assert!(should_send(NotifyWhen::OnChange, &new_items));
+        assert!(!should_send(NotifyWhen::OnFailure, &new_items));
+        assert!(should_send(NotifyWhen::OnFailure, &failed_items));
+        assert!(should_send(NotifyWhen::OnFailure, &failed));
+        assert!(!should_send(NotifyWhen::Digest, &failed));
+
+        assert_eq!(failed.summary(), "SearchFilesTask failed: timeout in 0s, found 10, new 0, downloaded 0, failed 0, 2048 bytes");
+        let swept = RunReport { temp_files: Some(SweepSummary { resumable: 1, deleted: 2, kept: 0 }), ..quiet.clone() };
+        assert!(swept.summary().ends_with("2048 bytes, temp files: 1 to resume, 2 deleted, 0 kept"));
+        let paused = RunReport {
+            skipped: 4,
+            paused: vec![PauseReason::QuotaReached { destination: "usb".to_owned(), library_size: 100, quota: 150 }],
+            ..quiet.clone()
+        };
+        assert!(paused.summary().ends_with("2048 bytes, skipped 4, paused: library size in usb 100 bytes reached quota of 150 bytes"));
+        assert!(!paused.is_failure());
+        assert!(should_send(NotifyWhen::OnChange, &paused));
+        let digest = Notification::Digest { profile: "nas".to_owned(), reports: &[quiet, failed] };
+        assert_eq!(digest.title(), "rs-google-photos-sync nas: 2 runs, 1 failed");
+        assert!(digest.is_failure());
+    }
+
+    // a minimal SMTP server standing in for MailHog
+    #[test]
+    pub fn smtp_notifier_delivers_the_report() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut received = String::new();
+            let mut in_data = false;
+
+            writer.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        writer.write_all(b"250 queued\r\n").unwrap();
+                    } else {
+                        received.push_str(&line);
+                    }
+                } else if line.starts_with("EHLO") {
+                    writer.write_all(b"250 localhost\r\n").unwrap();
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    writer.write_all(b"354 go ahead\r\n").unwrap();
+                } else if line.starts_with("QUIT") {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    writer.write_all(b"250 ok\r\n").unwrap();
+                }
+                line.clear();
+            }
+
+            received
+        });
+
+        let kind = NotifierKind::Smtp {
+            server: "127.0.0.1".to_owned(),
+            port: Some(port),
+            security: Some(SmtpSecurity::None),
+            username: None,
+            password: None,
+            from: "sync@example.com".to_owned(),
+            to: vec!["me@example.com".to_owned()],
+        };
+        let report = report(false, 0, 0);
+        let notification = Notification::Run(&report);
+
+        send_email_blocking(&kind, notification.title(), notification.text()).unwrap();
+        let received = server.join().unwrap();
+
+        assert!(received.contains("Subject: rs-google-photos-sync"));
+        assert!(received.contains("To: me@example.com"));
+        assert!(received.contains("SearchFilesTask failed: timeout"));
+    }
+}
@@ -0,0 +1,404 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use futures::StreamExt;
+use futures::stream;
+use image::imageops::FilterType;
+use image::GrayImage;
+use log::error;
+
+use crate::{MediaItemId, StoredItem, StoredItemStore};
+use crate::destinations::Destination;
+use crate::error::{CustomError, CustomResult};
+use crate::util;
+
+// cached in the catalog, sha256 is the content the hashes were computed from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PerceptualHash {
+    pub sha256: Option<String>,
+    pub dhash: String,
+    pub phash: String,
+}
+
+impl PerceptualHash {
+    fn bits(&self) -> Option<(u64, u64)> {
+        Some((u64::from_str_radix(&self.dhash, 16).ok()?, u64::from_str_radix(&self.phash, 16).ok()?))
+    }
+}
+
+// difference hash: each bit tells whether a pixel is brighter than its right neighbour on a 9x8 thumbnail
+pub fn dhash(image: &GrayImage) -> u64 {
+    let small = image::imageops::resize(image, 9, 8, FilterType::Triangle);
+    let mut hash = 0u64;
+
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+
+    hash
+}
+
+// DCT hash: low 8x8 frequencies of a 32x32 thumbnail compared against their median
+pub fn phash(image: &GrayImage) -> u64 {
+    const SIZE: usize = 32;
+    const LOW: usize = 8;
+
+    let small = image::imageops::resize(image, SIZE as u32, SIZE as u32, FilterType::Triangle);
+    let pixels = small.pixels().map(|pixel| pixel[0] as f64).collect::<Vec<_>>();
+
+    let cosines = (0..LOW)
+        .map(|u| (0..SIZE)
+            .map(|x| ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI / (2 * SIZE) as f64).cos())
+            .collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    // separable DCT-II, rows first, only the low frequencies are needed
+    let mut rows = vec![0f64; SIZE * LOW];
+    for y in 0..SIZE {
+        for u in 0..LOW {
+            rows[y * LOW + u] = (0..SIZE).map(|x| pixels[y * SIZE + x] * cosines[u][x]).sum();
+        }
+    }
+
+    let mut coefficients = Vec::with_capacity(LOW * LOW);
+    for cosine in &cosines {
+        for u in 0..LOW {
+            coefficients.push((0..SIZE).map(|y| rows[y * LOW + u] * cosine[y]).sum::<f64>());
+        }
+    }
+
+    // the DC term is the average brightness and says nothing about the picture
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let median = sorted[sorted.len() / 2];
+
+    coefficients.iter().fold(0u64, |hash, coefficient| (hash << 1) | (*coefficient > median) as u64)
+}
+
+pub fn hash_image(bytes: &[u8], sha256: Option<String>) -> CustomResult<PerceptualHash> {
+    let image = image::load_from_memory(bytes)
+        .map_err(|e| CustomError::Err(format!("cannot decode image {}", e)))?
+        .to_luma8();
+
+    Ok(PerceptualHash {
+        sha256,
+        dhash: format!("{:016x}", dhash(&image)),
+        phash: format!("{:016x}", phash(&image)),
+    })
+}
+
+#[derive(Debug, Default)]
+pub struct HashReport {
+    pub hashed: usize,
+    pub cached: usize,
+    pub failed: usize,
+}
+
+// Hashes the downloaded photos of a destination that have no hash cached for their current content.
+pub async fn hash_library(storage: &mut StoredItemStore, destination: &Destination, parallel: usize)
+                          -> CustomResult<HashReport>
+{
+    let mut report = HashReport::default();
+    let replica = destination.replica();
+
+    let mut pending = Vec::new();
+    for stored_item in storage.get_all() {
+        let info = match stored_item.get_download_info_at(replica) {
+            Some(info) if stored_item.is_photo() => info,
+            _ => continue,
+        };
+
+        match stored_item.perceptual.as_ref() {
+            Some(hash) if info.sha256.is_none() || hash.sha256 == info.sha256 => report.cached += 1,
+            _ => pending.push((stored_item.mediaItem.id.to_owned(), stored_item.get_filename(), info.sha256.clone())),
+        }
+    }
+
+    let backend = &*destination.backend;
+
+    let hashes = stream::iter(pending)
+        .map(|(id, filename, sha256)| async move {
+            let res = async {
+                let mut bytes = Vec::new();
+                let mut body = backend.get(&filename).await?;
+                while let Some(chunk) = body.next().await {
+                    bytes.extend_from_slice(&chunk?);
+                }
+
+                tokio::task::spawn_blocking(move || hash_image(&bytes, sha256)).await?
+            }.await;
+
+            match res {
+                Ok(hash) => Some((id, hash)),
+                Err(e) => {
+                    error!("Error hashing {} {}", filename, e);
+                    None
+                }
+            }
+        })
+        .buffer_unordered(parallel.max(1))
+        .collect::<Vec<_>>()
+        .await;
+
+    for hash in hashes {
+        match hash {
+            Some((id, hash)) => {
+                if let Some(mut stored_item) = storage.get_cloned(&id) {
+                    stored_item.perceptual = Some(hash);
+                    storage.set(&id, stored_item);
+                }
+                report.hashed += 1;
+            }
+            None => report.failed += 1,
+        }
+    }
+
+    storage.persist()?;
+
+    Ok(report)
+}
+
+fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+// BK-tree over the pHash, so finding the neighbours within a small distance does not compare every pair
+struct BkTree {
+    nodes: Vec<(u64, usize, HashMap<u32, usize>)>,
+}
+
+impl BkTree {
+    fn new() -> BkTree {
+        BkTree { nodes: Vec::new() }
+    }
+
+    fn insert(&mut self, hash: u64, item: usize) {
+        let new = self.nodes.len();
+        self.nodes.push((hash, item, HashMap::new()));
+
+        if new == 0 {
+            return;
+        }
+
+        let mut current = 0;
+        loop {
+            let d = distance(self.nodes[current].0, hash);
+            match self.nodes[current].2.get(&d) {
+                Some(&child) => current = child,
+                None => {
+                    self.nodes[current].2.insert(d, new);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn find(&self, hash: u64, max_distance: u32) -> Vec<usize> {
+        let mut found = Vec::new();
+        let mut stack = if self.nodes.is_empty() { vec![] } else { vec![0] };
+
+        while let Some(node) = stack.pop() {
+            let (node_hash, item, children) = &self.nodes[node];
+            let d = distance(*node_hash, hash);
+
+            if d <= max_distance {
+                found.push(*item);
+            }
+
+            for (child_distance, child) in children {
+                if *child_distance + max_distance >= d && *child_distance <= d + max_distance {
+                    stack.push(*child);
+                }
+            }
+        }
+
+        found
+    }
+}
+
+// Items are near-duplicates when both their pHash and dHash differ in at most max_distance bits.
+// Clusters are the connected groups of near-duplicates, largest first.
+pub fn find_clusters(stored_items: &[&StoredItem], max_distance: u32) -> Vec<Vec<MediaItemId>> {
+    let hashed = stored_items
+        .iter()
+        .filter_map(|stored_item| Some((stored_item, stored_item.perceptual.as_ref()?.bits()?)))
+        .collect::<Vec<_>>();
+
+    let mut tree = BkTree::new();
+    for (i, (_, (_, phash))) in hashed.iter().enumerate() {
+        tree.insert(*phash, i);
+    }
+
+    let mut parents = (0..hashed.len()).collect::<Vec<_>>();
+    fn root(parents: &mut [usize], mut i: usize) -> usize {
+        while parents[i] != i {
+            parents[i] = parents[parents[i]];
+            i = parents[i];
+        }
+        i
+    }
+
+    for (i, (_, (dhash, phash))) in hashed.iter().enumerate() {
+        for j in tree.find(*phash, max_distance) {
+            if j != i && distance(*dhash, (hashed[j].1).0) <= max_distance {
+                let (a, b) = (root(&mut parents, i), root(&mut parents, j));
+                parents[a.max(b)] = a.min(b);
+            }
+        }
+    }
+
+    let mut clusters: HashMap<usize, Vec<&StoredItem>> = HashMap::new();
+    for (i, (stored_item, _)) in hashed.iter().enumerate() {
+        let cluster = root(&mut parents, i);
+        clusters.entry(cluster).or_default().push(stored_item);
+    }
+
+    let mut clusters = clusters
+        .into_values()
+        .filter(|cluster| cluster.len() > 1)
+        .map(|mut cluster| {
+            cluster.sort_by(|a, b| a.creation_time().cmp(&b.creation_time()).then_with(|| a.mediaItem.id.cmp(&b.mediaItem.id)));
+            cluster.iter().map(|stored_item| stored_item.mediaItem.id.to_owned()).collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    clusters.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+
+    clusters
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    Json,
+    Html,
+}
+
+impl FromStr for ReportFormat {
+    type Err = CustomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ReportFormat::Json),
+            "html" => Ok(ReportFormat::Html),
+            _ => Err(CustomError::Err(format!("unknown report format {}, expected json or html", s))),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[allow(non_snake_case)]
+struct ReportItem<'a> {
+    id: &'a str,
+    filename: String,
+    productUrl: String,
+    creationTime: String,
+    width: Option<&'a str>,
+    height: Option<&'a str>,
+    size: Option<u64>,
+    dhash: Option<&'a str>,
+    phash: Option<&'a str>,
+}
+
+pub fn write_report(storage: &StoredItemStore, clusters: &[Vec<MediaItemId>], format: ReportFormat, path: &Path)
+                    -> CustomResult<()>
+{
+    let clusters = clusters
+        .iter()
+        .map(|cluster| cluster
+            .iter()
+            .filter_map(|id| storage.get(id))
+            .map(|stored_item| ReportItem {
+                id: &stored_item.mediaItem.id,
+                filename: stored_item.get_filename(),
+                productUrl: stored_item.mediaItem.get_product_url(),
+                creationTime: stored_item.creation_time().to_rfc3339(),
+                width: stored_item.mediaItem.mediaMetadata.width.as_deref(),
+                height: stored_item.mediaItem.mediaMetadata.height.as_deref(),
+                size: stored_item.get_download_info().and_then(|info| info.size),
+                dhash: stored_item.perceptual.as_ref().map(|hash| hash.dhash.as_str()),
+                phash: stored_item.perceptual.as_ref().map(|hash| hash.phash.as_str()),
+            })
+            .collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    let content = match format {
+        ReportFormat::Json => serde_json::to_string_pretty(&serde_json::json!({ "clusters": clusters }))?,
+        ReportFormat::Html => {
+            let mut html = String::from("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Near-duplicates</title>\n\
+                <style>body{font-family:sans-serif} table{border-collapse:collapse;margin-bottom:2em} \
+                td,th{border:1px solid #ccc;padding:4px 8px;text-align:left}</style></head><body>\n");
+            html.push_str(&format!("<h1>{} clusters of near-duplicates</h1>\n", clusters.len()));
+
+            for (i, cluster) in clusters.iter().enumerate() {
+                html.push_str(&format!("<h2>Cluster {}</h2>\n<table><tr><th>File</th><th>Created</th>\
+                    <th>Size</th><th>Dimensions</th></tr>\n", i + 1));
+
+                for item in cluster {
+                    html.push_str(&format!("<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td><td>{}x{}</td></tr>\n",
+                                           util::html_escape(&item.productUrl), util::html_escape(&item.filename),
+                                           item.creationTime, item.size.map(|size| size.to_string()).unwrap_or_default(),
+                                           item.width.unwrap_or("?"), item.height.unwrap_or("?")));
+                }
+
+                html.push_str("</table>\n");
+            }
+
+            html.push_str("</body></html>\n");
+            html
+        }
+    };
+
+    fs::write(path, content)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use image::{GrayImage, Luma};
+
+    use super::*;
+
+    fn gradient(width: u32, height: u32, noise: u8) -> GrayImage {
+        GrayImage::from_fn(width, height, |x, y| {
+            let value = (x * 255 / width + y * 64 / height) as u8;
+            Luma([value.saturating_add(if (x + y) % 7 == 0 { noise } else { 0 })])
+        })
+    }
+
+    #[test]
+    pub fn resized_copy_has_close_hashes() {
+        let original = gradient(640, 480, 0);
+        let resized = image::imageops::resize(&original, 320, 240, FilterType::Lanczos3);
+        let noisy = gradient(640, 480, 6);
+        let flipped = image::imageops::flip_horizontal(&original);
+
+        assert!(distance(phash(&original), phash(&resized)) <= 4);
+        assert!(distance(dhash(&original), dhash(&resized)) <= 4);
+        assert!(distance(phash(&original), phash(&noisy)) <= 4);
+        assert!(distance(dhash(&original), dhash(&flipped)) > 16);
+    }
+
+    #[test]
+    pub fn bk_tree_finds_hashes_within_distance() {
+        let mut tree = BkTree::new();
+        for (i, hash) in [0b0000u64, 0b0001, 0b0111, 0b1111_1111].iter().enumerate() {
+            tree.insert(*hash, i);
+        }
+
+        let mut found = tree.find(0, 1);
+        found.sort();
+        assert_eq!(found, vec![0, 1]);
+
+        let mut found = tree.find(0b0011, 1);
+        found.sort();
+        assert_eq!(found, vec![1, 2]);
+    }
+}
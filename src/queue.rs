@@ -0,0 +1,129 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::Receiver;
+use std::thread;
+
+use log::info;
+
+use crate::scheduling::JobTask;
+
+#[derive(Default)]
+struct QueueState {
+    // in arrival order, at most one task of each type
+    pending: Vec<JobTask>,
+    running: Option<&'static str>,
+    closed: bool,
+}
+
+// Tasks sent to the channel wait here until the receiver takes the next one.
+// A task of a type that is already pending replaces it, one of the type that is running is skipped,
+// so a long download does not leave a pile of downloads to run back to back.
+#[derive(Clone)]
+pub struct TaskQueue {
+    state: Arc<(Mutex<QueueState>, Condvar)>,
+}
+
+impl TaskQueue {
+    // moves the tasks from rx into the queue until every sender is gone
+    pub fn start(rx: Receiver<JobTask>) -> TaskQueue {
+        let queue = TaskQueue { state: Arc::new((Mutex::new(QueueState::default()), Condvar::new())) };
+
+        let forwarder = queue.clone();
+        thread::spawn(move || {
+            for task in rx {
+                forwarder.push(task);
+            }
+
+            forwarder.state.0.lock().unwrap().closed = true;
+            forwarder.state.1.notify_all();
+        });
+
+        queue
+    }
+
+    fn push(&self, task: JobTask) {
+        let mut state = self.state.0.lock().unwrap();
+
+        if state.running == Some(task.name()) {
+            info!("{} is already running, skipped", task.name());
+            return;
+        }
+
+        match state.pending.iter().position(|pending| pending.name() == task.name()) {
+            Some(i) => {
+                info!("{} is already pending, coalesced", task.name());
+                state.pending[i] = task;
+            }
+            None => state.pending.push(task),
+        }
+
+        self.state.1.notify_all();
+    }
+
+    // Blocks until a task is pending and marks it running, the previous task is done by then.
+    // None once every sender is gone and nothing is left.
+    pub fn next(&self) -> Option<JobTask> {
+        let mut state = self.state.0.lock().unwrap();
+        state.running = None;
+
+        while state.pending.is_empty() && !state.closed {
+            state = self.state.1.wait(state).unwrap();
+        }
+
+        // the first of the most urgent tasks
+        let (i, _) = state.pending.iter().enumerate().min_by_key(|(i, task)| (task.priority(), *i))?;
+        let task = state.pending.remove(i);
+        state.running = Some(task.name());
+
+        Some(task)
+    }
+
+    pub fn pending(&self) -> Vec<&'static str> {
+        self.state.0.lock().unwrap().pending.iter().map(|task| task.name()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::scheduling::SearchWindow;
+
+    fn wait_for_pending(queue: &TaskQueue, count: usize) {
+        let started = Instant::now();
+        while queue.pending().len() < count && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    pub fn tasks_are_coalesced_skipped_while_running_and_taken_by_priority() {
+        let (tx, rx) = mpsc::channel();
+        let queue = TaskQueue::start(rx);
+
+        tx.send(JobTask::DownloadFilesTask(50)).unwrap();
+        wait_for_pending(&queue, 1);
+        assert_eq!(queue.next().map(|task| task.name()), Some("DownloadFilesTask"));
+
+        // sent while the download runs
+        for _ in 0..5 {
+            tx.send(JobTask::DownloadFilesTask(50)).unwrap();
+            tx.send(JobTask::SearchFilesTask(SearchWindow::DaysBack(10), 100)).unwrap();
+        }
+        tx.send(JobTask::SearchFilesTask(SearchWindow::SinceLastSearch, 200)).unwrap();
+        tx.send(JobTask::RefreshTokenTask).unwrap();
+        wait_for_pending(&queue, 2);
+        drop(tx);
+
+        let mut taken = Vec::new();
+        while let Some(task) = queue.next() {
+            taken.push((task.name(), task.params()));
+        }
+
+        assert_eq!(taken, vec![
+            ("RefreshTokenTask", String::new()),
+            ("SearchFilesTask", "since_last_search limit=200".to_owned()),
+        ]);
+    }
+}
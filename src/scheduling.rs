@@ -1,49 +1,216 @@
+use std::fmt;
 use std::sync::mpsc::{Sender};
 use std::thread;
 use std::time::Duration;
 
+use chrono::{DateTime, Utc};
 use job_scheduler::{Job, JobScheduler, Schedule};
+use log::{error, info};
 
+use crate::config::{self, Config};
 use crate::error::CustomResult;
+use crate::history::{HistoryStore, JobHistory};
+use crate::perceptual::ReportFormat;
+use crate::status::SharedStatus;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 
+// how far back in creation time a search goes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchWindow {
+    DaysBack(i32),
+    // back to the start of the last successful search plus search_overlap_days,
+    // search_days_back when there was none
+    SinceLastSearch,
+    // the whole library, for items uploaded late with an old creation date
+    All,
+}
+
+impl fmt::Display for SearchWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SearchWindow::DaysBack(days_back) => write!(f, "days_back={}", days_back),
+            SearchWindow::SinceLastSearch => write!(f, "since_last_search"),
+            SearchWindow::All => write!(f, "all"),
+        }
+    }
+}
+
+impl SearchWindow {
+    // days back to search, None for the whole library
+    pub fn days_back(&self, config: &Config, history: &HistoryStore, now: DateTime<Utc>) -> Option<i32> {
+        match self {
+            SearchWindow::DaysBack(days_back) => Some(*days_back),
+            SearchWindow::SinceLastSearch => {
+                let last_search = history.last_succeeded(&["SearchFilesTask", "SyncFilesTask", "DeepScanTask"]);
+
+                Some(match last_search {
+                    Some(started_at) => {
+                        let days = (now.signed_duration_since(started_at).num_hours() + 23) / 24;
+                        days as i32 + config.get_search_overlap_days()
+                    }
+                    None => config.search_days_back,
+                })
+            }
+            SearchWindow::All => None,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub enum JobTask {
     RefreshTokenTask,
     DownloadFilesTask(i32),
-    SearchFilesTask(i32, usize)
+    SearchFilesTask(SearchWindow, usize),
+    SyncFilesTask(SearchWindow, usize),
+    DeepScanTask,
+    VerifyFilesTask(bool),
+    DedupFilesTask,
+    DuplicatesTask(ReportFormat, String),
+    GalleryTask,
+    DigestTask,
 }
 
-pub fn run_job_scheduler(tx: Sender<JobTask>, stop_flag: Arc<AtomicBool>) -> CustomResult<()> {
-    let config = crate::config::Config::new()?;
+impl JobTask {
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobTask::RefreshTokenTask => "RefreshTokenTask",
+            JobTask::DownloadFilesTask(_) => "DownloadFilesTask",
+            JobTask::SearchFilesTask(..) => "SearchFilesTask",
+            JobTask::SyncFilesTask(..) => "SyncFilesTask",
+            JobTask::DeepScanTask => "DeepScanTask",
+            JobTask::VerifyFilesTask(_) => "VerifyFilesTask",
+            JobTask::DedupFilesTask => "DedupFilesTask",
+            JobTask::DuplicatesTask(..) => "DuplicatesTask",
+            JobTask::GalleryTask => "GalleryTask",
+            JobTask::DigestTask => "DigestTask",
+        }
+    }
 
-    let refresh_task_schedule: Schedule = String::from(config.refresh_token_schedule.to_owned()).parse()?;
-    let search_task_schedule: Schedule = config.search_new_items_schedule.parse()?;
-    let download_task_schedule: Schedule = config.download_photos_schedule.parse()?;
+    // lower runs first, the token is refreshed before anything that needs it
+    pub fn priority(&self) -> u8 {
+        match self {
+            JobTask::RefreshTokenTask => 0,
+            JobTask::SearchFilesTask(..) | JobTask::SyncFilesTask(..) | JobTask::DeepScanTask => 1,
+            JobTask::DownloadFilesTask(_) => 2,
+            _ => 3,
+        }
+    }
 
-    thread::spawn(move || {
-        let mut sched = JobScheduler::new();
+    // recorded in the job history
+    pub fn params(&self) -> String {
+        match self {
+            JobTask::DownloadFilesTask(num_files) => format!("num_files={}", num_files),
+            JobTask::SearchFilesTask(window, limit) | JobTask::SyncFilesTask(window, limit) =>
+                format!("{} limit={}", window, limit),
+            JobTask::VerifyFilesTask(unmark) => format!("unmark={}", unmark),
+            JobTask::DuplicatesTask(format, output) => format!("format={:?} output={}", format, output),
+            _ => String::new(),
+        }
+    }
+}
 
-        let tx1 = tx.clone();
-        sched.add(Job::new(refresh_task_schedule, move || {
-            tx1.send(JobTask::RefreshTokenTask).unwrap();
-        }));
+pub type NextRuns = Vec<(&'static str, Option<DateTime<Utc>>)>;
 
-        let tx2 = tx.clone();
-        let search_days_back = config.search_days_back;
-        let search_limit = config.search_limit;
-        sched.add(Job::new(search_task_schedule, move || {
-            tx2.send(JobTask::SearchFilesTask(search_days_back, search_limit)).unwrap();
-        }));
+// the scheduled search, also started from the dashboard
+pub fn search_task(config: &Config) -> JobTask {
+    let window = match config.get_adaptive_search() {
+        true => SearchWindow::SinceLastSearch,
+        false => SearchWindow::DaysBack(config.search_days_back),
+    };
+
+    match config.pipelined_search.unwrap_or(false) {
+        true => JobTask::SyncFilesTask(window, config.search_limit),
+        false => JobTask::SearchFilesTask(window, config.search_limit),
+    }
+}
+
+// the jobs run on a schedule, each with the task it sends
+fn scheduled_jobs(config: &Config) -> CustomResult<Vec<(Schedule, JobTask)>> {
+    let mut jobs = vec![
+        (config.refresh_token_schedule.parse()?, JobTask::RefreshTokenTask),
+        (config.search_new_items_schedule.parse()?, search_task(config)),
+        (config.download_photos_schedule.parse()?, JobTask::DownloadFilesTask(config.get_download_files_per_run())),
+    ];
+
+    if config.get_adaptive_search() {
+        jobs.push((config.get_deep_scan_schedule().parse()?, JobTask::DeepScanTask));
+    }
+
+    let notify = config.get_notify();
+    if notify.has_digest() {
+        jobs.push((notify.get_digest_schedule().parse()?, JobTask::DigestTask));
+    }
+
+    Ok(jobs)
+}
+
+// next time each scheduled job fires, the digest is left out as it does not sync anything
+pub fn next_runs(config: &Config) -> CustomResult<NextRuns> {
+    Ok(scheduled_jobs(config)?
+        .into_iter()
+        .filter(|(_, task)| !matches!(task, JobTask::DigestTask))
+        .map(|(schedule, task)| (task.name(), schedule.upcoming(Utc).next()))
+        .collect())
+}
+
+// Jobs that should have fired since their last recorded run, or have never run.
+// Each is run once, however many runs were missed. Token refreshes are not recorded,
+// the token is renewed on startup anyway.
+pub fn missed_jobs(config: &Config, history: &HistoryStore, now: DateTime<Utc>) -> CustomResult<Vec<JobTask>> {
+    let mut missed = Vec::new();
+
+    for (schedule, task) in scheduled_jobs(config)? {
+        let is_missed = match history.last_started(task.name()) {
+            Some(last_started) => schedule.after(&last_started).next().is_some_and(|next| next < now),
+            None => true,
+        };
+
+        if is_missed && !matches!(task, JobTask::DigestTask | JobTask::RefreshTokenTask) {
+            missed.push(task);
+        }
+    }
+
+    Ok(missed)
+}
 
-        let tx3 = tx.clone();
-        let download_files_parallel = config.download_files_parallel;
-        sched.add(Job::new(download_task_schedule, move || {
-            tx3.send(JobTask::DownloadFilesTask(download_files_parallel)).unwrap();
+fn job_scheduler(jobs: Vec<(Schedule, JobTask)>, tx: &Sender<JobTask>) -> JobScheduler<'static> {
+    let mut sched = JobScheduler::new();
+
+    for (schedule, task) in jobs {
+        let tx = tx.clone();
+        sched.add(Job::new(schedule, move || {
+            tx.send(task.clone()).unwrap();
         }));
+    }
+
+    sched
+}
+
+// A reloaded config replaces every job at once, between two ticks
+pub fn run_job_scheduler(tx: Sender<JobTask>, stop_flag: Arc<AtomicBool>, status: SharedStatus) -> CustomResult<()> {
+    let mut version = config::version();
+    let jobs = scheduled_jobs(&config::current())?;
+
+    status.lock().unwrap().scheduler_tick = Some(Utc::now());
+
+    thread::spawn(move || {
+        let mut sched = job_scheduler(jobs, &tx);
 
         loop {
+            if config::version() != version {
+                version = config::version();
+                match scheduled_jobs(&config::current()) {
+                    Ok(jobs) => {
+                        sched = job_scheduler(jobs, &tx);
+                        info!("rescheduled jobs for config version {}", version);
+                    }
+                    Err(e) => error!("Error rescheduling jobs {}", e),
+                }
+            }
+
             sched.tick();
+            status.lock().unwrap().scheduler_tick = Some(Utc::now());
 
             std::thread::sleep(Duration::from_millis(500));
 
@@ -55,3 +222,50 @@ pub fn run_job_scheduler(tx: Sender<JobTask>, stop_flag: Arc<AtomicBool>) -> Cus
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::history::JobHistory;
+    use crate::notify::RunReport;
+    use crate::status::RunCounts;
+
+    #[test]
+    pub fn missed_jobs_and_the_search_window_come_from_the_history() {
+        let path = std::env::temp_dir().join("rs-google-photos-sync-missed.data");
+        let _ = std::fs::remove_file(&path);
+        let mut history = HistoryStore::new(path.to_str().unwrap());
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "refresh_token_schedule": "0 0 * * * *",
+            "search_new_items_schedule": "0 0 3 * * *",
+            "download_photos_schedule": "0 0/5 * * * *",
+            "search_days_back": 10,
+            "search_limit": 500,
+            "download_files_parallel": 4,
+            "storage_location": "photos",
+            "fix_downloaded_info": { "mark_downloaded": false, "unmark_downloaded": false }
+        })).unwrap();
+        let now = Utc.with_ymd_and_hms(2020, 5, 4, 12, 2, 0).unwrap();
+
+        for (task, started_at) in [("SearchFilesTask", Utc.with_ymd_and_hms(2020, 5, 1, 3, 0, 0).unwrap()),
+                                   ("DownloadFilesTask", now - chrono::Duration::minutes(1))] {
+            let mut report = RunReport::new(String::new(), task, "", task, started_at, &RunCounts::default(), 0, &Ok(()));
+            report.started_at = started_at;
+            history.record(report, 10);
+        }
+
+        let missed = missed_jobs(&config, &history, now).unwrap();
+        assert_eq!(missed.iter().map(|task| task.name()).collect::<Vec<_>>(), vec!["SearchFilesTask", "DeepScanTask"]);
+        assert_eq!(missed[0].params(), "since_last_search limit=500");
+
+        // three days and nine hours since the last search, plus the overlap
+        assert_eq!(SearchWindow::SinceLastSearch.days_back(&config, &history, now), Some(6));
+        assert_eq!(SearchWindow::All.days_back(&config, &history, now), None);
+
+        history.data.clear();
+        assert_eq!(missed_jobs(&config, &history, now).unwrap().len(), 3);
+        assert_eq!(SearchWindow::SinceLastSearch.days_back(&config, &history, now), Some(10));
+    }
+}
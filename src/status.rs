@@ -0,0 +1,172 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+
+use crate::{MediaItemId, StoredItemStore};
+use crate::disk_space::PauseReason;
+use crate::downloader::Downloads;
+use crate::error::CustomResult;
+use crate::temp_files::SweepSummary;
+
+const MAX_RECENT_ERRORS: usize = 20;
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct CatalogTotals {
+    pub searched: usize,
+    pub downloaded: usize,
+    pub pending: usize,
+    // pending items whose last download attempt failed
+    pub failed: usize,
+}
+
+// what the running task has done so far, reset when the next one starts
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct RunCounts {
+    pub found: usize,
+    pub new: usize,
+    pub downloaded: usize,
+    pub failed: usize,
+    // not tried because their destinations were paused, left for a later run
+    pub skipped: usize,
+    pub paused: Vec<PauseReason>,
+    // a search that stopped at its limit before the last page
+    pub truncated: bool,
+    // the .tmp files swept on startup, counted in the first run after it
+    pub temp_files: Option<SweepSummary>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TaskError {
+    pub task: String,
+    pub at: DateTime<Utc>,
+    pub message: String,
+}
+
+// What the scheduler and the task receiver have been doing, shared with the http server
+#[derive(Serialize, Debug, Default)]
+pub struct Status {
+    pub totals: CatalogTotals,
+    pub token_expires_at: Option<DateTime<Utc>>,
+    // set once the catalog is loaded and reconciled with the destinations
+    pub catalog_loaded_at: Option<DateTime<Utc>>,
+    pub scheduler_tick: Option<DateTime<Utc>>,
+    pub running: Option<String>,
+    pub running_since: Option<DateTime<Utc>>,
+    pub run_counts: RunCounts,
+    // the destinations paused by the last run that downloaded, until the next one
+    pub download_paused: Vec<PauseReason>,
+    pub last_success: HashMap<String, DateTime<Utc>>,
+    pub recent_errors: VecDeque<TaskError>,
+    #[serde(skip)]
+    failed_downloads: HashSet<MediaItemId>,
+}
+
+pub type SharedStatus = Arc<Mutex<Status>>;
+
+impl Status {
+    pub fn task_started(&mut self, task: &str) {
+        self.running = Some(task.to_owned());
+        self.running_since = Some(Utc::now());
+        self.run_counts = RunCounts::default();
+    }
+
+    pub fn task_finished(&mut self, task: &str, res: &CustomResult<()>) {
+        self.running = None;
+        self.running_since = None;
+
+        match res {
+            Ok(_) => {
+                self.last_success.insert(task.to_owned(), Utc::now());
+            }
+            Err(e) => self.record_error(task, e.to_string()),
+        }
+    }
+
+    pub fn record_error(&mut self, task: &str, message: String) {
+        if self.recent_errors.len() == MAX_RECENT_ERRORS {
+            self.recent_errors.pop_back();
+        }
+
+        self.recent_errors.push_front(TaskError { task: task.to_owned(), at: Utc::now(), message });
+    }
+
+    pub fn record_found(&mut self, found: usize, new: usize) {
+        self.run_counts.found += found;
+        self.run_counts.new += new;
+    }
+
+    pub fn record_truncated(&mut self) {
+        self.run_counts.truncated = true;
+    }
+
+    pub fn record_sweep(&mut self, sweep: SweepSummary) {
+        self.run_counts.temp_files = Some(sweep);
+    }
+
+    pub fn record_pauses(&mut self, pauses: Vec<PauseReason>) {
+        self.run_counts.paused = pauses.clone();
+        self.download_paused = pauses;
+    }
+
+    pub fn record_downloads(&mut self, selected: &[MediaItemId], downloads: &Downloads) {
+        let downloaded = downloads.copies.iter().map(|copy| &copy.id).collect::<HashSet<_>>();
+
+        for id in selected {
+            if downloaded.contains(id) {
+                self.failed_downloads.remove(id);
+                self.run_counts.downloaded += 1;
+            } else if downloads.skipped.contains(id) {
+                self.run_counts.skipped += 1;
+            } else {
+                self.failed_downloads.insert(id.to_owned());
+                self.run_counts.failed += 1;
+            }
+        }
+    }
+
+    pub fn update_totals(&mut self, storage: &StoredItemStore, replicas: &[&str]) {
+        let mut totals = CatalogTotals::default();
+
+        for stored_item in storage.get_all() {
+            totals.searched += 1;
+
+            if stored_item.is_downloaded_everywhere(replicas) {
+                totals.downloaded += 1;
+            } else {
+                totals.pending += 1;
+                if self.failed_downloads.contains(&stored_item.mediaItem.id) {
+                    totals.failed += 1;
+                }
+            }
+        }
+
+        self.totals = totals;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::downloader::DownloadedCopy;
+    use crate::integrity::FileDigest;
+
+    #[test]
+    pub fn items_skipped_by_a_pause_are_not_failed() {
+        let copy = DownloadedCopy {
+            id: "a".to_owned(),
+            replica: None,
+            digest: FileDigest { size: 10, sha256: None },
+            linked_to: None,
+        };
+        let downloads = Downloads { copies: vec![copy], skipped: vec!["c".to_owned()] };
+        let selected = ["a", "b", "c"].map(str::to_owned);
+
+        let mut status = Status::default();
+        status.record_downloads(&selected, &downloads);
+
+        assert_eq!((status.run_counts.downloaded, status.run_counts.failed, status.run_counts.skipped), (1, 1, 1));
+        assert!(status.failed_downloads.contains("b"));
+        assert!(!status.failed_downloads.contains("c"));
+    }
+}
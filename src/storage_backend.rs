@@ -0,0 +1,250 @@
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use filetime::FileTime;
+use futures::stream::{self, BoxStream, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::config::Config;
+use crate::error::{CustomError, CustomResult};
+use crate::s3::{S3Backend, S3Config};
+
+pub type ByteStream<'a> = BoxStream<'a, CustomResult<Bytes>>;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StorageConfig {
+    // path defaults to storage_location
+    Local { path: Option<String> },
+    S3(S3Config),
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub name: String,
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+// Where downloaded files end up. Names are flat file names, a backend maps them onto its own layout.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    fn describe(&self) -> String;
+
+    // set only for backends on a local disk, used for the free space and case sensitivity checks
+    fn local_root(&self) -> Option<&Path> {
+        None
+    }
+
+    // whether put can continue an existing object from its current size
+    fn supports_append(&self) -> bool {
+        false
+    }
+
+    // whether an object only shows up once put completes, it is then written under its final name
+    // instead of a .tmp that is renamed afterwards
+    fn atomic_put(&self) -> bool {
+        false
+    }
+
+    async fn prepare(&self) -> CustomResult<()>;
+
+    // offset is the size of the existing object to append to, 0 to create or replace it,
+    // the object is left with mtime as its modification time
+    async fn put(&self, name: &str, offset: u64, size: Option<u64>, mtime: DateTime<Utc>, body: ByteStream<'_>) -> CustomResult<()>;
+
+    async fn get(&self, name: &str) -> CustomResult<ByteStream<'static>>;
+
+    async fn stat(&self, name: &str) -> CustomResult<Option<ObjectInfo>>;
+
+    async fn list(&self) -> CustomResult<Vec<ObjectInfo>>;
+
+    async fn rename(&self, from: &str, to: &str) -> CustomResult<()>;
+
+    async fn set_mtime(&self, name: &str, mtime: DateTime<Utc>) -> CustomResult<()>;
+
+    async fn remove(&self, name: &str) -> CustomResult<()>;
+
+    // replaces name with a hardlink or reflink of existing, false when the backend cannot link
+    async fn link(&self, _existing: &str, _name: &str, _reflink: bool) -> CustomResult<bool> {
+        Ok(false)
+    }
+}
+
+pub fn create_backend(storage: &StorageConfig, config: &Config, client: &reqwest::Client)
+                      -> CustomResult<Box<dyn StorageBackend>>
+{
+    match storage {
+        StorageConfig::Local { path } => {
+            Ok(Box::new(LocalBackend::new(path.as_ref().unwrap_or(&config.storage_location))))
+        }
+        StorageConfig::S3(s3_config) => Ok(Box::new(S3Backend::new(s3_config, client.clone())?)),
+    }
+}
+
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub fn new(root: &str) -> LocalBackend {
+        LocalBackend { root: PathBuf::from(root) }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalBackend {
+    fn describe(&self) -> String {
+        format!("{}", self.root.display())
+    }
+
+    fn local_root(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+
+    fn supports_append(&self) -> bool {
+        true
+    }
+
+    async fn prepare(&self) -> CustomResult<()> {
+        tokio::fs::create_dir_all(&self.root).await
+            .map_err(|e| CustomError::Err(format!("error creating dir all {} {}", self.root.display(), e)))
+    }
+
+    async fn put(&self, name: &str, offset: u64, _size: Option<u64>, mtime: DateTime<Utc>, mut body: ByteStream<'_>) -> CustomResult<()> {
+        let path = self.root.join(name);
+
+        let mut dest = if offset > 0 {
+            let existing = tokio::fs::metadata(&path).await?.len();
+            if existing != offset {
+                return Err(CustomError::Err(format!("cannot append to {} at {}, it has {} bytes", name, offset, existing)));
+            }
+            tokio::fs::OpenOptions::new().append(true).open(&path).await?
+        } else {
+            tokio::fs::File::create(&path).await?
+        };
+
+        while let Some(chunk) = body.next().await {
+            dest.write_all(&chunk?).await?;
+        }
+
+        dest.flush().await?;
+        drop(dest);
+
+        self.set_mtime(name, mtime).await
+    }
+
+    async fn get(&self, name: &str) -> CustomResult<ByteStream<'static>> {
+        let file = tokio::fs::File::open(self.root.join(name)).await?;
+
+        Ok(stream::try_unfold(file, |mut file| async move {
+            let mut buf = vec![0u8; 64 * 1024];
+            let read = file.read(&mut buf).await?;
+
+            if read == 0 {
+                return Ok(None);
+            }
+
+            buf.truncate(read);
+            Ok(Some((Bytes::from(buf), file)))
+        }).boxed())
+    }
+
+    async fn stat(&self, name: &str) -> CustomResult<Option<ObjectInfo>> {
+        match tokio::fs::metadata(self.root.join(name)).await {
+            Ok(metadata) if metadata.is_file() => Ok(Some(ObjectInfo {
+                name: name.to_owned(),
+                size: metadata.len(),
+                modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+            })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list(&self) -> CustomResult<Vec<ObjectInfo>> {
+        let mut objects = Vec::new();
+
+        if !tokio::fs::try_exists(&self.root).await? {
+            return Ok(objects);
+        }
+
+        let mut entries = tokio::fs::read_dir(&self.root).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+
+            if let (true, Ok(name)) = (metadata.is_file(), entry.file_name().into_string()) {
+                objects.push(ObjectInfo {
+                    name,
+                    size: metadata.len(),
+                    modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+                });
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> CustomResult<()> {
+        tokio::fs::rename(self.root.join(from), self.root.join(to)).await?;
+
+        Ok(())
+    }
+
+    async fn set_mtime(&self, name: &str, mtime: DateTime<Utc>) -> CustomResult<()> {
+        let path = self.root.join(name);
+        tokio::task::spawn_blocking(move || {
+            filetime::set_file_mtime(path, FileTime::from_unix_time(mtime.timestamp(), 0))
+        }).await??;
+
+        Ok(())
+    }
+
+    async fn remove(&self, name: &str) -> CustomResult<()> {
+        tokio::fs::remove_file(self.root.join(name)).await?;
+
+        Ok(())
+    }
+
+    async fn link(&self, existing: &str, name: &str, reflink: bool) -> CustomResult<bool> {
+        let existing = self.root.join(existing);
+        let tmp = self.root.join(format!("{}.tmp", name));
+        let _ = tokio::fs::remove_file(&tmp).await;
+
+        let res = tokio::task::spawn_blocking({
+            let (existing, tmp) = (existing.clone(), tmp.clone());
+            move || if reflink { clone_file(&existing, &tmp) } else { std::fs::hard_link(&existing, &tmp) }
+        }).await?;
+        res.map_err(|e| CustomError::Err(format!("cannot link {} to {} {}", name, existing.display(), e)))?;
+
+        tokio::fs::rename(&tmp, self.root.join(name)).await?;
+
+        Ok(true)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn clone_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let src = std::fs::File::open(from)?;
+    let dest = std::fs::File::create(to)?;
+
+    if unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } == -1 {
+        let e = std::io::Error::last_os_error();
+        drop(dest);
+        let _ = std::fs::remove_file(to);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clone_file(_from: &Path, _to: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Other, "reflinks are only supported on Linux"))
+}
@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use chrono::Utc;
+use log::error;
+
+use crate::StoredItemStore;
+use crate::error::CustomResult;
+use crate::storage_backend::StorageBackend;
+
+const TEMP_SUFFIX: &str = ".tmp";
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct SweepSummary {
+    pub resumable: usize,
+    pub deleted: usize,
+    pub kept: usize,
+}
+
+impl SweepSummary {
+    pub fn add(&mut self, other: &SweepSummary) {
+        self.resumable += other.resumable;
+        self.deleted += other.deleted;
+        self.kept += other.kept;
+    }
+}
+
+// A .tmp that still belongs to a not downloaded item and is younger than max_age is left in place,
+// the downloader continues it with a range request. Stale and empty ones are deleted, others are kept
+// until they are old enough.
+pub async fn sweep_temp_files(storage: &StoredItemStore, backend: &dyn StorageBackend, replica: Option<&str>,
+                              max_age: Duration, fold_case: bool) -> CustomResult<SweepSummary>
+{
+    let mut summary = SweepSummary::default();
+
+    let fold = |name: String| if fold_case { name.to_lowercase() } else { name };
+
+    let pending = storage.get_all()
+        .into_iter()
+        .filter(|stored_item| stored_item.get_download_info_at(replica).is_none())
+        .map(|stored_item| fold(stored_item.get_filename()))
+        .collect::<HashSet<_>>();
+
+    let now = Utc::now();
+
+    for object in backend.list().await? {
+        if !object.name.ends_with(TEMP_SUFFIX) {
+            continue;
+        }
+
+        let age = object.modified
+            .and_then(|modified| now.signed_duration_since(modified).to_std().ok())
+            .unwrap_or_default();
+        let target = fold(object.name[..object.name.len() - TEMP_SUFFIX.len()].to_owned());
+
+        if age > max_age || object.size == 0 {
+            match backend.remove(&object.name).await {
+                Ok(_) => summary.deleted += 1,
+                Err(e) => {
+                    error!("Error deleting {} {}", object.name, e);
+                    summary.kept += 1;
+                }
+            }
+        } else if pending.contains(&target) {
+            summary.resumable += 1;
+        } else {
+            summary.kept += 1;
+        }
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::time::SystemTime;
+
+    use filetime::FileTime;
+
+    use super::*;
+    use crate::storage_backend::LocalBackend;
+    use crate::test::StoredItemBuilder;
+
+    #[test]
+    pub fn sweep_keeps_resumable_and_deletes_stale_temp_files() {
+        let dir = std::env::temp_dir().join("rs-google-photos-sync-sweep");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut storage = StoredItemStore::new(dir.join("data").to_str().unwrap());
+        StoredItemBuilder::new("1", "a.jpg").add_to(&mut storage);
+        StoredItemBuilder::new("2", "b.jpg").add_to(&mut storage);
+
+        fs::write(dir.join("a.jpg.tmp"), b"partial").unwrap();
+        fs::write(dir.join("b.jpg.tmp"), b"partial").unwrap();
+        fs::write(dir.join("c.jpg.tmp"), b"partial").unwrap();
+        fs::write(dir.join("d.jpg.tmp"), b"").unwrap();
+
+        let two_days_ago = SystemTime::now() - Duration::from_secs(48 * 60 * 60);
+        filetime::set_file_mtime(dir.join("b.jpg.tmp"), FileTime::from_system_time(two_days_ago)).unwrap();
+
+        let backend = LocalBackend::new(dir.to_str().unwrap());
+        let sweep = sweep_temp_files(&storage, &backend, None, Duration::from_secs(24 * 60 * 60), false);
+        let summary = tokio::runtime::Runtime::new().unwrap().block_on(sweep).unwrap();
+
+        assert_eq!(summary, SweepSummary { resumable: 1, deleted: 2, kept: 1 });
+        assert!(dir.join("a.jpg.tmp").exists());
+        assert!(!dir.join("b.jpg.tmp").exists());
+        assert!(dir.join("c.jpg.tmp").exists());
+        assert!(!dir.join("d.jpg.tmp").exists());
+    }
+}
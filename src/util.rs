@@ -6,6 +6,7 @@ use serde_json;
 
 use crate::error::CustomResult;
 use std::cmp::min;
+use std::time::Duration;
 
 pub fn read_json_file<T>(path: String) -> CustomResult<T>
     where T: DeserializeOwned
@@ -37,3 +38,32 @@ pub fn split_into_groups<T>(items: &Vec<T>, group_size: usize) -> Vec<Vec<&T>>
 
     groups
 }
+
+// One client for the whole app, so connections to Google are pooled and reused (HTTP/2 where offered)
+pub fn create_http_client() -> CustomResult<reqwest::Client> {
+    let client = reqwest::Client::builder()
+        .pool_idle_timeout(Duration::from_secs(90))
+        .tcp_keepalive(Duration::from_secs(60))
+        .build()?;
+
+    Ok(client)
+}
+
+pub fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// percent-encodes everything but the RFC 3986 unreserved characters
+pub fn uri_encode(value: &str, encode_slash: bool) -> String {
+    value.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            b'/' if !encode_slash => "/".to_owned(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}